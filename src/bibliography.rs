@@ -0,0 +1,305 @@
+//! BibTeX/BibLaTeX entry keys and a checker that matches them against citations in Markdown documents.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::citation::*;
+use crate::parser::*;
+use crate::types::*;
+
+/// Entry of a `.bib` file, e.g. `@book{grune_parsingtechniques_en_1990, ...}`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct BibEntry {
+  pub key: String,
+  /// Entry type without `@`, e.g. `book`.
+  pub kind: Range,
+  pub key_range: Range,
+  /// The whole entry, from `@` to the closing brace.
+  pub range: Range,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Bibliography {
+  entries: Vec<BibEntry>,
+  /// Index of the first entry with each key.
+  keys: HashMap<String, usize>,
+}
+
+impl Bibliography {
+  /// Parses entries of a `.bib` file. `@comment`, `@string` and `@preamble` are skipped, and so is everything outside
+  /// of entries.
+  #[must_use]
+  pub fn parse(input: &str, encoding: Encoding) -> Self {
+    let bytes = input.as_bytes();
+    let mut cursor = Position::default();
+    let mut position = |offset| {
+      cursor = encoding.advance(input, cursor, offset);
+      cursor
+    };
+
+    let mut bibliography = Self::default();
+    let mut offset = 0;
+    while let Some(at) = bytes[offset..].iter().position(|&byte| byte == b'@') {
+      let at = offset + at;
+      offset = at + 1;
+
+      let kind_end = at + 1 + bytes[at + 1..].iter().take_while(|byte| byte.is_ascii_alphabetic()).count();
+      let open = skip_whitespace(bytes, kind_end);
+      if kind_end == at + 1 || !matches!(bytes.get(open), Some(b'{' | b'(')) {
+        continue;
+      }
+      let Some(end) = entry_end(bytes, open) else {
+        // An unbalanced entry would swallow the rest of the file, so parsing resumes at the next line that starts
+        // with `@`.
+        match bytes[open..].windows(2).position(|pair| pair == b"\n@") {
+          Some(newline) => offset = open + newline + 1,
+          None => break,
+        }
+        continue;
+      };
+      offset = end;
+
+      let kind = &input[at + 1..kind_end];
+      if ["comment", "string", "preamble"].iter().any(|special| kind.eq_ignore_ascii_case(special)) {
+        continue;
+      }
+
+      let key_start = skip_whitespace(bytes, open + 1);
+      let key_length = bytes[key_start..end].iter().take_while(|&&byte| !is_key_terminator(byte)).count();
+      if key_length == 0 {
+        continue;
+      }
+
+      let start = position(at);
+      let kind = Range { start: position(at + 1), end: position(kind_end) };
+      let key_range = Range { start: position(key_start), end: position(key_start + key_length) };
+      let range = Range { start, end: position(end) };
+      let key = input[key_start..key_start + key_length].to_owned();
+
+      bibliography.keys.entry(key.clone()).or_insert(bibliography.entries.len());
+      bibliography.entries.push(BibEntry { key, kind, key_range, range });
+    }
+
+    bibliography
+  }
+
+  #[must_use]
+  pub fn entries(&self) -> &[BibEntry] {
+    &self.entries
+  }
+
+  #[must_use]
+  pub fn get(&self, key: &str) -> Option<&BibEntry> {
+    self.keys.get(key).map(|&i| &self.entries[i])
+  }
+}
+
+/// Offset right after the brace (or parenthesis) that closes the entry opened at `open`.
+fn entry_end(bytes: &[u8], open: usize) -> Option<usize> {
+  let close = if bytes[open] == b'{' { b'}' } else { b')' };
+  let mut depth = 0usize;
+
+  for (i, &byte) in bytes.iter().enumerate().skip(open + 1) {
+    match byte {
+      b'{' => depth += 1,
+      b'}' if depth > 0 => depth -= 1,
+      _ if byte == close && depth == 0 => return Some(i + 1),
+      _ => {}
+    }
+  }
+
+  None
+}
+
+const fn is_key_terminator(byte: u8) -> bool {
+  matches!(byte, b',' | b'}' | b')' | b'{' | b'(') || byte.is_ascii_whitespace()
+}
+
+fn skip_whitespace(bytes: &[u8], offset: usize) -> usize {
+  offset + bytes[offset..].iter().take_while(|byte| byte.is_ascii_whitespace()).count()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BibDiagnosticKind {
+  /// Citation key that isn't in the bibliography.
+  MissingKey,
+  /// Bibliography entry that is never cited.
+  UnusedEntry,
+  /// Bibliography entry with the same key as a previous one.
+  DuplicateKey,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct BibDiagnostic {
+  pub kind: BibDiagnosticKind,
+  /// Markdown document for missing keys, `.bib` file otherwise.
+  pub path: PathBuf,
+  pub range: Range,
+  pub key: String,
+}
+
+/// Matches citations of Markdown documents against a bibliography.
+pub struct CitationChecker {
+  bib_path: PathBuf,
+  bibliography: Bibliography,
+  encoding: Encoding,
+  strategy: Strategy,
+  cited: Vec<bool>,
+  diagnostics: Vec<BibDiagnostic>,
+}
+
+impl CitationChecker {
+  /// `encoding` is used for positions of both the bibliography and the documents.
+  #[must_use]
+  pub fn new(bib_path: PathBuf, bibliography: Bibliography, encoding: Encoding, strategy: Strategy) -> Self {
+    let cited = vec![false; bibliography.entries.len()];
+    Self { bib_path, bibliography, encoding, strategy, cited, diagnostics: vec![] }
+  }
+
+  /// Reports citation keys of the document that aren't in the bibliography.
+  pub fn check_document(&mut self, path: &Path, input: &str) {
+    for citation in parse_citations_with_encoding(input, self.encoding, self.strategy) {
+      for cite in citation.cites {
+        let key = &input[cite.key.start.offset..cite.key.end.offset];
+        if let Some(&i) = self.bibliography.keys.get(key) {
+          self.cited[i] = true;
+        } else {
+          let kind = BibDiagnosticKind::MissingKey;
+          self.diagnostics.push(BibDiagnostic { kind, path: path.to_owned(), range: cite.key, key: key.to_owned() });
+        }
+      }
+    }
+  }
+
+  /// Reports duplicate and unused entries, and returns all diagnostics.
+  #[must_use]
+  pub fn finish(mut self) -> Vec<BibDiagnostic> {
+    for (i, entry) in self.bibliography.entries.iter().enumerate() {
+      let kind = match self.bibliography.keys.get(&entry.key) {
+        Some(&first) if first != i => BibDiagnosticKind::DuplicateKey,
+        Some(&first) if !self.cited[first] => BibDiagnosticKind::UnusedEntry,
+        _ => continue,
+      };
+      let path = self.bib_path.clone();
+      self.diagnostics.push(BibDiagnostic { kind, path, range: entry.key_range, key: entry.key.clone() });
+    }
+
+    self.diagnostics
+  }
+}
+
+/// Reads the bibliography and the Markdown documents and checks them with [`CitationChecker`].
+///
+/// # Errors
+///
+/// Returns an error if any of the files can't be read.
+pub fn check_files(
+  bib_path: &Path,
+  markdown_paths: &[PathBuf],
+  encoding: Encoding,
+  strategy: Strategy,
+) -> io::Result<Vec<BibDiagnostic>> {
+  let bibliography = Bibliography::parse(&std::fs::read_to_string(bib_path)?, encoding);
+  let mut checker = CitationChecker::new(bib_path.to_owned(), bibliography, encoding, strategy);
+
+  for path in markdown_paths {
+    checker.check_document(path, &std::fs::read_to_string(path)?);
+  }
+
+  Ok(checker.finish())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tests::test_data::*;
+
+  static BIB_INPUT: &str = r#"Comments are allowed @ here.
+@string{ acm = "ACM" }
+@Book{grune_parsingtechniques_en_1990,
+  title = {Parsing Techniques: {A} Practical Guide},
+  year = 1990,
+}
+@comment{@article{commented_out, title = {}}}
+@article ( смирнов_2020 , title = "Статья" )
+@misc{grune_parsingtechniques_en_1990}
+"#;
+
+  #[test]
+  pub fn parse_bibliography_test() {
+    let bibliography = Bibliography::parse(BIB_INPUT, Encoding::Utf16);
+    let entries = bibliography.entries();
+
+    let keys: Vec<_> = entries.iter().map(|entry| entry.key.as_str()).collect();
+    assert_eq!(keys, ["grune_parsingtechniques_en_1990", "смирнов_2020", "grune_parsingtechniques_en_1990"]);
+
+    let kinds: Vec<_> =
+      entries.iter().map(|entry| &BIB_INPUT[entry.kind.start.offset..entry.kind.end.offset]).collect();
+    assert_eq!(kinds, ["Book", "article", "misc"]);
+
+    let range = entries[1].key_range;
+    assert_eq!((range.start.line, range.start.character, range.end.character), (7, 11, 23));
+    let range = entries[0].range;
+    assert_eq!((range.start.line, range.start.character, range.end.line, range.end.character), (2, 0, 5, 1));
+
+    assert_eq!(bibliography.get("смирнов_2020"), Some(&entries[1]));
+    assert_eq!(bibliography.get("commented_out"), None);
+  }
+
+  #[test]
+  pub fn parse_unbalanced_entry_test() {
+    let input = "@book{a, title = {A}\n@book{b, title = {B}}\n@misc{c,\n  note = {{}\n}";
+    let bibliography = Bibliography::parse(input, Encoding::Utf32);
+
+    let keys: Vec<_> = bibliography.entries().iter().map(|entry| entry.key.as_str()).collect();
+    assert_eq!(keys, ["b"]);
+    assert_eq!(bibliography.entries()[0].range.start.line, 1);
+  }
+
+  #[test]
+  pub fn check_citations_test() {
+    let bibliography = Bibliography::parse(BIB_INPUT, Encoding::Utf32);
+    let mut checker = CitationChecker::new("refs.bib".into(), bibliography, Encoding::Utf32, Strategy::Vector128);
+    checker.check_document(Path::new("a.md"), LONG_MULTILINE_INPUT);
+    checker.check_document(Path::new("b.md"), "Ы [@missing; @grune_parsingtechniques_en_1990]");
+
+    let actual: Vec<_> = checker
+      .finish()
+      .into_iter()
+      .map(|diagnostic| {
+        let range = diagnostic.range;
+        (diagnostic.kind, diagnostic.path, diagnostic.key, range.start.line, range.start.character)
+      })
+      .collect();
+    assert_eq!(
+      actual,
+      [
+        (BibDiagnosticKind::MissingKey, "b.md".into(), "missing".into(), 0, 4),
+        (BibDiagnosticKind::UnusedEntry, "refs.bib".into(), "смирнов_2020".into(), 7, 11),
+        (BibDiagnosticKind::DuplicateKey, "refs.bib".into(), "grune_parsingtechniques_en_1990".into(), 8, 6),
+      ]
+    );
+  }
+
+  #[test]
+  pub fn check_files_test() {
+    let directory = std::env::temp_dir().join(format!("parsing-sandbox-bibliography-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let bib_path = directory.join("refs.bib");
+    let markdown_path = directory.join("note.md");
+    std::fs::write(&bib_path, "@book{a, title = {A}}\n@book{b, title = {B}}").unwrap();
+    std::fs::write(&markdown_path, "[@a]\n[@c, 1]").unwrap();
+
+    let diagnostics =
+      check_files(&bib_path, std::slice::from_ref(&markdown_path), Encoding::Utf16, Strategy::Vector256).unwrap();
+    std::fs::remove_dir_all(&directory).unwrap();
+
+    let actual: Vec<_> =
+      diagnostics.iter().map(|diagnostic| (diagnostic.kind, &diagnostic.path, &diagnostic.key[..])).collect();
+    assert_eq!(
+      actual,
+      [(BibDiagnosticKind::MissingKey, &markdown_path, "c"), (BibDiagnosticKind::UnusedEntry, &bib_path, "b")]
+    );
+  }
+}
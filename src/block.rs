@@ -0,0 +1,600 @@
+//! First pass of CommonMark parsing: classification of lines into a tree of blocks. Inline content (the second pass)
+//! isn't parsed.
+
+use crate::code::*;
+use crate::outline::*;
+use crate::parser::*;
+use crate::types::*;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BlockKind {
+  Paragraph,
+  /// `#` to `######` heading with its level.
+  AtxHeading(usize),
+  /// Paragraph underlined with `===` (level 1) or `---` (level 2).
+  SetextHeading(usize),
+  /// Code between fences of 3 or more backticks or tildes, including the fences.
+  FencedCode,
+  /// Lines indented by 4 or more spaces.
+  IndentedCode,
+  BlockQuote,
+  /// Consecutive list items with the same kind of marker.
+  List {
+    ordered: bool,
+  },
+  ListItem,
+  ThematicBreak,
+  BlankLine,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Block {
+  pub kind: BlockKind,
+  /// From the block quote marker, list marker or the first character of the block to the end of its last line.
+  pub range: Range,
+  /// Index of the containing block quote, list or list item, or `None` for top-level blocks.
+  pub parent: Option<usize>,
+}
+
+/// Parses blocks with the strategy. Blocks are in document order, parents before their children.
+#[must_use]
+pub fn parse_blocks(input: &str, encoding: Encoding, strategy: Strategy) -> Vec<Block> {
+  let lines = lines(input, strategy);
+  let mut parser = BlockParser::new(input.as_bytes());
+  for (i, &line) in lines.iter().enumerate() {
+    if i + 1 == lines.len() && line.start == line.end && i > 0 {
+      break;
+    }
+    parser.parse_line(i, line);
+  }
+  parser.close_tip();
+
+  let position = |bound: Bound| {
+    let start = Position { line: bound.line, character: 0, offset: lines[bound.line].start };
+    encoding.advance(input, start, bound.offset)
+  };
+  parser
+    .blocks
+    .into_iter()
+    .map(|block| Block {
+      kind: block.kind,
+      range: Range { start: position(block.start), end: position(block.end) },
+      parent: block.parent,
+    })
+    .collect()
+}
+
+/// Indices of the children of the block, or of the top-level blocks if `parent` is `None`.
+pub fn children(blocks: &[Block], parent: Option<usize>) -> impl Iterator<Item = usize> + '_ {
+  let start = parent.map_or(0, |parent| parent + 1);
+  (start..blocks.len()).filter(move |&i| blocks[i].parent == parent)
+}
+
+/// Line and offset of a block boundary. Converted into a [`Position`] once all blocks are found.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct Bound {
+  line: usize,
+  offset: usize,
+}
+
+/// Line that is being parsed and the offset and column in it up to which it's parsed.
+#[derive(Clone, Copy, Debug)]
+struct Cursor {
+  line: usize,
+  end: usize,
+  offset: usize,
+  column: usize,
+}
+
+impl Cursor {
+  /// Moves to `offset` at `column` and skips `columns` columns of spaces and tabs from there. A tab that only
+  /// partially fits is skipped entirely.
+  fn consume(&mut self, bytes: &[u8], offset: usize, column: usize, columns: usize) {
+    (self.offset, self.column) = (offset, column);
+    let target = column + columns;
+    while self.offset < self.end && self.column < target {
+      match bytes[self.offset] {
+        b' ' => self.column += 1,
+        b'\t' => self.column += 4 - self.column % 4,
+        _ => break,
+      }
+      self.offset += 1;
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct RawBlock {
+  kind: BlockKind,
+  start: Bound,
+  end: Bound,
+  parent: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Container {
+  BlockQuote,
+  /// List item whose content is indented by `indent` columns relative to the parent container.
+  ListItem {
+    indent: usize,
+  },
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Open {
+  block: usize,
+  container: Container,
+}
+
+/// Leaf block that can continue on the next line.
+#[derive(Clone, Copy, Debug)]
+enum Tip {
+  Paragraph(usize),
+  IndentedCode(usize),
+  FencedCode { block: usize, fence: u8, length: usize },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Marker {
+  /// `-`, `+` or `*`.
+  Bullet(u8),
+  /// Number followed by `.` or `)`.
+  Ordered { delimiter: u8, number: usize },
+}
+
+impl Marker {
+  const fn continues(self, list: Self) -> bool {
+    match (self, list) {
+      (Self::Bullet(a), Self::Bullet(b)) => a == b,
+      (Self::Ordered { delimiter: a, .. }, Self::Ordered { delimiter: b, .. }) => a == b,
+      _ => false,
+    }
+  }
+}
+
+struct BlockParser<'a> {
+  bytes: &'a [u8],
+  blocks: Vec<RawBlock>,
+  /// Open block quotes and list items, outermost first.
+  open: Vec<Open>,
+  tip: Option<Tip>,
+  /// List that the next list item is added to, for the top level and each open container.
+  lists: Vec<Option<(usize, Marker)>>,
+  /// Blank lines after indented code, which belong to it if the code continues.
+  blank_lines: Vec<(Bound, Bound)>,
+}
+
+impl<'a> BlockParser<'a> {
+  fn new(bytes: &'a [u8]) -> Self {
+    Self { bytes, blocks: vec![], open: vec![], tip: None, lists: vec![None], blank_lines: vec![] }
+  }
+
+  fn parse_line(&mut self, line: usize, Line { start, end }: Line) {
+    let mut cursor = Cursor { line, end, offset: start, column: 0 };
+    let mut matched = self.match_containers(&mut cursor);
+    if matched == self.open.len() && self.continue_fenced_code(cursor) {
+      return;
+    }
+    let started = self.start_containers(&mut cursor, &mut matched);
+
+    let (columns, next) = indentation(self.bytes, cursor.offset, end, cursor.column);
+    let blank = next == end;
+    if let (Some(Tip::Paragraph(paragraph)), false, false) = (self.tip, started, blank) {
+      if matched < self.open.len() && self.is_lazy_continuation(cursor, columns, next) {
+        self.extend(paragraph, Bound { line, offset: end });
+        return;
+      }
+    }
+
+    self.close_containers(matched);
+    if blank {
+      self.add_blank_line(cursor);
+    } else {
+      self.add_leaf(cursor, columns, next);
+    }
+  }
+
+  /// Skips markers of the open containers that the line continues and returns their number.
+  fn match_containers(&self, cursor: &mut Cursor) -> usize {
+    let bytes = self.bytes;
+    let mut matched = 0;
+    while let Some(open) = self.open.get(matched) {
+      let (columns, next) = indentation(bytes, cursor.offset, cursor.end, cursor.column);
+      match open.container {
+        Container::BlockQuote if columns <= 3 && next < cursor.end && bytes[next] == b'>' => {
+          cursor.consume(bytes, next + 1, cursor.column + columns + 1, 1);
+        }
+        Container::ListItem { .. } if next == cursor.end => {}
+        Container::ListItem { indent } if columns >= indent => {
+          cursor.consume(bytes, cursor.offset, cursor.column, indent);
+        }
+        _ => break,
+      }
+      matched += 1;
+    }
+    matched
+  }
+
+  /// Adds the line to the open fenced code block, if there is one.
+  fn continue_fenced_code(&mut self, cursor: Cursor) -> bool {
+    let Some(Tip::FencedCode { block, fence, length }) = self.tip else {
+      return false;
+    };
+
+    let Cursor { line, end, .. } = cursor;
+    let (columns, next) = indentation(self.bytes, cursor.offset, end, cursor.column);
+    let run = if next < end { run_length(&self.bytes[..end], next, fence) } else { 0 };
+    if columns <= 3 && run >= length && self.bytes[next + run..end].iter().all(|&byte| is_space(byte)) {
+      self.extend(block, Bound { line, offset: next + run });
+      self.tip = None;
+    } else {
+      self.extend(block, Bound { line, offset: end });
+    }
+    true
+  }
+
+  /// Opens block quotes and list items that start on the line, and returns whether there were any.
+  fn start_containers(&mut self, cursor: &mut Cursor, matched: &mut usize) -> bool {
+    let bytes = self.bytes;
+    let Cursor { line, end, .. } = *cursor;
+    let mut started = false;
+
+    loop {
+      let (columns, next) = indentation(bytes, cursor.offset, end, cursor.column);
+      if columns > 3 || next == end || is_thematic_break(bytes, next, end) {
+        break;
+      }
+
+      if bytes[next] == b'>' {
+        self.close_containers(*matched);
+        self.close_tip();
+        let block = self.push(BlockKind::BlockQuote, Bound { line, offset: next }, Bound { line, offset: next + 1 });
+        self.open.push(Open { block, container: Container::BlockQuote });
+        cursor.consume(bytes, next + 1, cursor.column + columns + 1, 1);
+      } else {
+        let Some((marker, marker_end)) = list_marker(bytes, next, end) else {
+          break;
+        };
+        let marker_column = cursor.column + columns + marker_end - next;
+        let (spaces, content) = indentation(bytes, marker_end, end, marker_column);
+        let empty = content == end;
+        // Only the first item of a list can interrupt a paragraph, and only if it's not empty and doesn't start with a
+        // number other than 1.
+        let continues_list = matches!(self.lists[*matched], Some((_, list_marker)) if marker.continues(list_marker));
+        let interrupts_paragraph = !started && !continues_list && matches!(self.tip, Some(Tip::Paragraph(_)));
+        if interrupts_paragraph && (empty || matches!(marker, Marker::Ordered { number, .. } if number != 1)) {
+          break;
+        }
+
+        self.close_containers(*matched);
+        self.close_tip();
+        let spaces = if empty || spaces > 4 { 1 } else { spaces };
+        let block = self.push_list_item(marker, Bound { line, offset: next }, Bound { line, offset: marker_end });
+        let indent = columns + marker_end - next + spaces;
+        self.open.push(Open { block, container: Container::ListItem { indent } });
+        if empty {
+          (cursor.offset, cursor.column) = (end, marker_column);
+        } else {
+          cursor.consume(bytes, marker_end, marker_column, spaces);
+        }
+      }
+
+      self.lists.push(None);
+      *matched = self.open.len();
+      started = true;
+    }
+
+    started
+  }
+
+  /// Whether a line that doesn't continue all open containers continues the open paragraph instead of closing it.
+  fn is_lazy_continuation(&self, cursor: Cursor, columns: usize, next: usize) -> bool {
+    let (bytes, end) = (self.bytes, cursor.end);
+    columns > 3
+      || !(atx_heading(bytes, next, end).is_some()
+        || fence_start(bytes, next, end).is_some()
+        || is_thematic_break(bytes, next, end))
+  }
+
+  fn add_blank_line(&mut self, cursor: Cursor) {
+    let start = Bound { line: cursor.line, offset: cursor.offset };
+    let end = Bound { line: cursor.line, offset: cursor.end };
+    if let Some(Tip::IndentedCode(_)) = self.tip {
+      self.blank_lines.push((start, end));
+      if let Some(parent) = self.parent() {
+        self.extend(parent, end);
+      }
+    } else {
+      self.close_tip();
+      self.push(BlockKind::BlankLine, start, end);
+    }
+  }
+
+  fn add_leaf(&mut self, cursor: Cursor, columns: usize, next: usize) {
+    let bytes = self.bytes;
+    let Cursor { line, end, .. } = cursor;
+    let first = Bound { line, offset: next };
+    let line_end = Bound { line, offset: end };
+
+    if columns > 3 {
+      match self.tip {
+        Some(Tip::Paragraph(block)) => self.extend(block, line_end),
+        Some(Tip::IndentedCode(block)) => {
+          self.blank_lines.clear();
+          self.extend(block, line_end);
+        }
+        _ => {
+          self.close_tip();
+          let mut code = cursor;
+          code.consume(bytes, cursor.offset, cursor.column, 4);
+          let block = self.push(BlockKind::IndentedCode, Bound { line, offset: code.offset }, line_end);
+          self.tip = Some(Tip::IndentedCode(block));
+        }
+      }
+    } else if let Some((level, ..)) = atx_heading(bytes, next, end) {
+      self.close_tip();
+      self.push(BlockKind::AtxHeading(level), first, line_end);
+    } else if let Some((fence, length)) = fence_start(bytes, next, end) {
+      self.close_tip();
+      let block = self.push(BlockKind::FencedCode, first, line_end);
+      self.tip = Some(Tip::FencedCode { block, fence, length });
+    } else if let (Some(Tip::Paragraph(block)), Some(level)) = (self.tip, setext_underline(bytes, next, end)) {
+      self.blocks[block].kind = BlockKind::SetextHeading(level);
+      self.extend(block, line_end);
+      self.tip = None;
+    } else if is_thematic_break(bytes, next, end) {
+      self.close_tip();
+      self.push(BlockKind::ThematicBreak, first, line_end);
+    } else if let Some(Tip::Paragraph(block)) = self.tip {
+      self.extend(block, line_end);
+    } else {
+      self.close_tip();
+      let block = self.push(BlockKind::Paragraph, first, line_end);
+      self.tip = Some(Tip::Paragraph(block));
+    }
+  }
+
+  fn parent(&self) -> Option<usize> {
+    self.open.last().map(|open| open.block)
+  }
+
+  /// Adds a block to the innermost open container and extends the container (and its ancestors) to the block end.
+  fn push(&mut self, kind: BlockKind, start: Bound, end: Bound) -> usize {
+    if !matches!(kind, BlockKind::BlankLine) {
+      self.lists[self.open.len()] = None;
+    }
+    self.push_with_parent(kind, start, end, self.parent())
+  }
+
+  fn push_with_parent(&mut self, kind: BlockKind, start: Bound, end: Bound, parent: Option<usize>) -> usize {
+    let block = self.blocks.len();
+    self.blocks.push(RawBlock { kind, start, end, parent });
+    self.extend(block, end);
+    block
+  }
+
+  /// Adds a list item to the current list, starting a new one if the marker doesn't continue it.
+  fn push_list_item(&mut self, marker: Marker, start: Bound, end: Bound) -> usize {
+    let depth = self.open.len();
+    let list = match self.lists[depth] {
+      Some((list, list_marker)) if marker.continues(list_marker) => list,
+      _ => {
+        let ordered = matches!(marker, Marker::Ordered { .. });
+        let list = self.push(BlockKind::List { ordered }, start, end);
+        self.lists[depth] = Some((list, marker));
+        list
+      }
+    };
+    self.push_with_parent(BlockKind::ListItem, start, end, Some(list))
+  }
+
+  /// Moves the end of the block and its ancestors.
+  fn extend(&mut self, block: usize, end: Bound) {
+    let mut block = Some(block);
+    while let Some(i) = block {
+      self.blocks[i].end = end;
+      block = self.blocks[i].parent;
+    }
+  }
+
+  /// Closes containers after the first `count`, and the tip if it was in one of them.
+  fn close_containers(&mut self, count: usize) {
+    self.open.truncate(count);
+    self.lists.truncate(count + 1);
+
+    let tip_block = match self.tip {
+      Some(Tip::Paragraph(block) | Tip::IndentedCode(block) | Tip::FencedCode { block, .. }) => block,
+      None => return,
+    };
+    if self.blocks[tip_block].parent != self.parent() {
+      self.close_tip();
+    }
+  }
+
+  fn close_tip(&mut self) {
+    if let Some(Tip::IndentedCode(block)) = self.tip.take() {
+      let parent = self.blocks[block].parent;
+      for (start, end) in std::mem::take(&mut self.blank_lines) {
+        self.push_with_parent(BlockKind::BlankLine, start, end, parent);
+      }
+    }
+  }
+}
+
+/// Columns of spaces and tabs (which move to the next multiple of 4) at `offset`, and the offset after them.
+fn indentation(bytes: &[u8], mut offset: usize, end: usize, column: usize) -> (usize, usize) {
+  let mut columns = 0;
+  while offset < end {
+    match bytes[offset] {
+      b' ' => columns += 1,
+      b'\t' => columns += 4 - (column + columns) % 4,
+      _ => break,
+    }
+    offset += 1;
+  }
+  (columns, offset)
+}
+
+/// If an opening code fence starts at `first`, returns its byte and length.
+fn fence_start(bytes: &[u8], first: usize, end: usize) -> Option<(u8, usize)> {
+  let fence = bytes[first];
+  let length = run_length(&bytes[..end], first, fence);
+  let valid = match fence {
+    b'`' => !bytes[first + length..end].contains(&b'`'),
+    b'~' => true,
+    _ => false,
+  };
+  (valid && length >= 3).then_some((fence, length))
+}
+
+/// List marker at `first` and the offset right after it.
+fn list_marker(bytes: &[u8], first: usize, end: usize) -> Option<(Marker, usize)> {
+  let (marker, marker_end) = if let byte @ (b'-' | b'+' | b'*') = bytes[first] {
+    (Marker::Bullet(byte), first + 1)
+  } else {
+    let digits = bytes[first..end].iter().take_while(|byte| byte.is_ascii_digit()).count();
+    let delimiter = *bytes.get(first + digits).filter(|&&byte| byte == b'.' || byte == b')')?;
+    if !(1..=9).contains(&digits) {
+      return None;
+    }
+    let number = std::str::from_utf8(&bytes[first..first + digits]).ok()?.parse().ok()?;
+    (Marker::Ordered { delimiter, number }, first + digits + 1)
+  };
+  (marker_end == end || is_space(bytes[marker_end])).then_some((marker, marker_end))
+}
+
+#[cfg(test)]
+pub mod tests {
+  extern crate test;
+  use test::bench::Bencher;
+
+  use proptest::prelude::*;
+
+  use super::*;
+  use crate::parser::Strategy;
+  use crate::tests::test_data::*;
+
+  type Summary = (BlockKind, usize, usize, Option<usize>);
+
+  /// Kind, first and last lines, and parent of each block.
+  fn assert_blocks(input: &str, expected: &[Summary]) {
+    for strategy in Strategy::ALL {
+      let actual: Vec<_> = parse_blocks(input, Encoding::Utf16, strategy)
+        .iter()
+        .map(|block| (block.kind, block.range.start.line, block.range.end.line, block.parent))
+        .collect();
+      assert_eq!(actual, expected, "{}", strategy.name());
+    }
+  }
+
+  proptest! {
+    #[test]
+    fn parse_blocks_property_test(s in "[aя 1.#=\\-*>`~\\t\\n\\r]{300}") {
+      let expected = parse_blocks(&s, Encoding::Utf16, Strategy::Chars);
+      for strategy in Strategy::ALL {
+        assert_eq!(parse_blocks(&s, Encoding::Utf16, strategy), expected);
+      }
+    }
+  }
+
+  #[test]
+  pub fn parse_blocks_test() {
+    use BlockKind::*;
+
+    let input = concat!(
+      "Setext\n---\n",
+      "> quote\nlazy\n> - item\n",
+      "***\n",
+      "    indented\n\n    code\n\n",
+      "1. one\n2. two\n\n   ```\n   code\n\n   ```\n",
+      "3) three\n\n",
+      "text\n2. not a list\n    not code",
+    );
+    let expected = [
+      (SetextHeading(2), 0, 1, None),
+      (BlockQuote, 2, 4, None),
+      (Paragraph, 2, 3, Some(1)),
+      (List { ordered: false }, 4, 4, Some(1)),
+      (ListItem, 4, 4, Some(3)),
+      (Paragraph, 4, 4, Some(4)),
+      (ThematicBreak, 5, 5, None),
+      (IndentedCode, 6, 8, None),
+      (BlankLine, 9, 9, None),
+      (List { ordered: true }, 10, 16, None),
+      (ListItem, 10, 10, Some(9)),
+      (Paragraph, 10, 10, Some(10)),
+      (ListItem, 11, 16, Some(9)),
+      (Paragraph, 11, 11, Some(12)),
+      (BlankLine, 12, 12, Some(12)),
+      (FencedCode, 13, 16, Some(12)),
+      (List { ordered: true }, 17, 18, None),
+      (ListItem, 17, 18, Some(16)),
+      (Paragraph, 17, 17, Some(17)),
+      (BlankLine, 18, 18, Some(17)),
+      (Paragraph, 19, 21, None),
+    ];
+
+    assert_blocks(input, &expected);
+  }
+
+  #[test]
+  pub fn parse_blocks_test_data_test() {
+    use BlockKind::*;
+
+    let heading = |level, line| (AtxHeading(level), line, line, None);
+    let blank = |line, parent| (BlankLine, line, line, parent);
+    let list = |start, end, parent| (List { ordered: false }, start, end, parent);
+    let item = |expected: &mut Vec<Summary>, start, end, list| {
+      let item = expected.len();
+      expected.extend([(ListItem, start, end, Some(list)), (Paragraph, start, start, Some(item))]);
+    };
+
+    let mut expected = vec![blank(0, None), heading(1, 1), blank(2, None), heading(2, 3), blank(4, None)];
+    expected.push(list(5, 6, None));
+    item(&mut expected, 5, 6, 5);
+    expected.extend([blank(6, Some(6)), heading(2, 7), blank(8, None), list(9, 11, None)]);
+    item(&mut expected, 9, 11, 11);
+    expected.push(list(10, 11, Some(12)));
+    item(&mut expected, 10, 11, 14);
+    expected.extend([blank(11, Some(15)), heading(2, 12), blank(13, None), list(14, 19, None)]);
+    item(&mut expected, 14, 14, 20);
+    item(&mut expected, 15, 15, 20);
+    item(&mut expected, 16, 19, 20);
+    expected.push(list(17, 19, Some(25)));
+    item(&mut expected, 17, 19, 27);
+    expected.push(list(18, 19, Some(28)));
+    item(&mut expected, 18, 18, 30);
+    item(&mut expected, 19, 19, 30);
+
+    assert_blocks(SHORT_MULTILINE_INPUT, &expected);
+
+    let blocks = parse_blocks(SHORT_MULTILINE_INPUT, Encoding::Utf16, Strategy::Vector256);
+    assert_eq!(children(&blocks, Some(20)).collect::<Vec<_>>(), [21, 23, 25]);
+    assert_eq!(blocks[31].range.start, Position { line: 18, character: 4, offset: 500 });
+  }
+
+  #[bench]
+  pub fn parse_blocks_chars_bench(b: &mut Bencher) {
+    b.iter(|| parse_blocks(BENCHMARK_INPUT, Encoding::Utf16, Strategy::Chars).len());
+  }
+
+  #[bench]
+  pub fn parse_blocks_bytes_bench(b: &mut Bencher) {
+    b.iter(|| parse_blocks(BENCHMARK_INPUT, Encoding::Utf16, Strategy::Bytes).len());
+  }
+
+  #[bench]
+  pub fn parse_blocks_v128_bench(b: &mut Bencher) {
+    b.iter(|| parse_blocks(BENCHMARK_INPUT, Encoding::Utf16, Strategy::Vector128).len());
+  }
+
+  #[bench]
+  pub fn parse_blocks_v256_bench(b: &mut Bencher) {
+    b.iter(|| parse_blocks(BENCHMARK_INPUT, Encoding::Utf16, Strategy::Vector256).len());
+  }
+
+  #[bench]
+  pub fn parse_blocks_v128_portable_bench(b: &mut Bencher) {
+    b.iter(|| parse_blocks(BENCHMARK_INPUT, Encoding::Utf16, Strategy::Vector128Portable).len());
+  }
+}
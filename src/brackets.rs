@@ -0,0 +1,511 @@
+//! Bracket matching state shared by all parsers.
+
+use crate::code::*;
+use crate::parser::*;
+use crate::types::*;
+use crate::utf8::*;
+
+/// ASCII sequences that open and close a range, such as `[` and `]`, or `[[` and `]]`. Opening and closing sequences
+/// may be the same (e.g. `$…$`), in which case the sequence closes the innermost range if it was opened by the same
+/// delimiter, and opens a new one otherwise.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Delimiter {
+  open: Vec<u8>,
+  close: Vec<u8>,
+}
+
+impl Delimiter {
+  /// # Panics
+  ///
+  /// Panics if either byte isn't ASCII or is a line ending.
+  #[must_use]
+  pub fn new(open: u8, close: u8) -> Self {
+    Self::sequence(&[open], &[close])
+  }
+
+  /// # Panics
+  ///
+  /// Panics if either sequence is empty, or contains non-ASCII bytes or line endings.
+  #[must_use]
+  pub fn sequence(open: &[u8], close: &[u8]) -> Self {
+    for sequence in [open, close] {
+      assert!(!sequence.is_empty(), "delimiters can't be empty");
+      assert!(sequence.is_ascii(), "delimiters must be ASCII");
+      assert!(!sequence.contains(&b'\n') && !sequence.contains(&b'\r'), "delimiters can't contain line endings");
+    }
+    Self { open: open.to_vec(), close: close.to_vec() }
+  }
+
+  #[must_use]
+  pub fn open(&self) -> &[u8] {
+    &self.open
+  }
+
+  #[must_use]
+  pub fn close(&self) -> &[u8] {
+    &self.close
+  }
+}
+
+impl Default for Delimiter {
+  fn default() -> Self {
+    Self::new(b'[', b']')
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DiagnosticKind {
+  /// Opening bracket that's still open at the end of input (or of its line in single-line mode).
+  UnclosedBracket,
+  /// Closing bracket without an open range.
+  UnexpectedClosingBracket,
+  /// Opening bracket that would exceed the maximum depth.
+  NestingTooDeep,
+}
+
+/// Unmatched bracket. The range covers the bracket itself.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Diagnostic {
+  pub kind: DiagnosticKind,
+  pub range: Range,
+  /// Index of the delimiter the bracket belongs to.
+  pub delimiter: usize,
+}
+
+/// Stack of open brackets together with the ranges of closed ones.
+///
+/// With `max_depth` of 1 an opening bracket inside a range is ignored (and so is the closing bracket that follows the
+/// range), which is how the parsers have always behaved. Greater values allow ranges to be nested. Either way, ignored
+/// brackets are reported as diagnostics.
+///
+/// When several sequences match at the same position, the closing sequence of the innermost range wins, then the
+/// longest sequence (opening sequences win ties, and so do delimiters that come first).
+#[derive(Clone, Debug)]
+pub struct Brackets {
+  delimiters: Vec<Delimiter>,
+  leading_bytes: [bool; 128],
+  max_depth: usize,
+  escapes: bool,
+  code: bool,
+  single_line: bool,
+  /// End of the code span or fenced block that is being skipped.
+  code_end: usize,
+  /// Lengths of backtick runs that are known to have no closing run further in the input.
+  unclosed_code_spans: Vec<usize>,
+  stack: Vec<(Position, usize)>,
+  ranges: Vec<Range>,
+  depths: Vec<usize>,
+  kinds: Vec<usize>,
+  diagnostics: Vec<Diagnostic>,
+}
+
+impl Brackets {
+  #[must_use]
+  pub fn new(options: ParseOptions) -> Self {
+    let mut leading_bytes = [false; 128];
+    for delimiter in &options.delimiters {
+      leading_bytes[delimiter.open[0] as usize] = true;
+      leading_bytes[delimiter.close[0] as usize] = true;
+    }
+    if options.code {
+      leading_bytes[b'`' as usize] = true;
+      leading_bytes[b'~' as usize] = true;
+    }
+
+    Self {
+      delimiters: options.delimiters,
+      leading_bytes,
+      max_depth: options.max_depth,
+      escapes: options.escapes,
+      code: options.code,
+      single_line: options.single_line,
+      code_end: 0,
+      unclosed_code_spans: vec![],
+      stack: vec![],
+      ranges: vec![],
+      depths: vec![],
+      kinds: vec![],
+      diagnostics: vec![],
+    }
+  }
+
+  #[must_use]
+  pub fn delimiters(&self) -> &[Delimiter] {
+    &self.delimiters
+  }
+
+  /// First byte of every opening and closing sequence (and of code spans and fences in code-aware mode).
+  pub fn leading_bytes(&self) -> impl Iterator<Item = u8> + '_ {
+    (0..128).filter(|&byte| self.leading_bytes[byte as usize])
+  }
+
+  /// Whether a delimiter sequence may start with the byte.
+  #[inline]
+  #[must_use]
+  pub fn is_delimiter(&self, byte: u8) -> bool {
+    self.leading_bytes.get(byte as usize).copied().unwrap_or(false)
+  }
+
+  /// Whether an opening bracket would be accepted right now.
+  #[inline]
+  #[must_use]
+  pub const fn can_open(&self) -> bool {
+    self.stack.len() < self.max_depth
+  }
+
+  /// Whether a closing bracket would be accepted right now.
+  #[inline]
+  #[must_use]
+  pub const fn can_close(&self) -> bool {
+    !self.stack.is_empty()
+  }
+
+  /// Handles a delimiter sequence at `position` of the input. Returns the length of the sequence, or 0 if there's
+  /// none (or it's escaped with a backslash).
+  #[inline]
+  pub fn handle(&mut self, input: &[u8], position: Position) -> usize {
+    let rest = &input[position.offset..];
+    match rest.first() {
+      Some(&byte) if self.is_delimiter(byte) => {}
+      _ => return 0,
+    }
+    if self.single_line {
+      self.close_lines_before(position.line);
+    }
+    if position.offset < self.code_end || self.escapes && is_escaped(input, position.offset) {
+      return 0;
+    }
+    if self.code {
+      if let Some(length) = self.handle_code(input, position.offset) {
+        return length;
+      }
+    }
+
+    if let Some(&(_, delimiter)) = self.stack.last() {
+      let length = self.delimiters[delimiter].close.len();
+      if rest.starts_with(&self.delimiters[delimiter].close) {
+        self.close(position, delimiter, length);
+        return length;
+      }
+    }
+
+    // (length, is opening, delimiter)
+    let mut longest: Option<(usize, bool, usize)> = None;
+    for (i, delimiter) in self.delimiters.iter().enumerate() {
+      for (sequence, is_opening) in [(&delimiter.open, true), (&delimiter.close, false)] {
+        let is_longer = match longest {
+          Some((length, opening, _)) => sequence.len() > length || (sequence.len() == length && is_opening && !opening),
+          None => true,
+        };
+        if is_longer && rest.starts_with(sequence) {
+          longest = Some((sequence.len(), is_opening, i));
+        }
+      }
+    }
+
+    match longest {
+      Some((length, true, delimiter)) => {
+        self.open(position, delimiter);
+        length
+      }
+      Some((length, false, delimiter)) => {
+        self.close(position, delimiter, length);
+        length
+      }
+      None => 0,
+    }
+  }
+
+  /// Handles a run of backticks or tildes that may start code. Returns the length of the run, or `None` if it has
+  /// nothing to do with code.
+  fn handle_code(&mut self, input: &[u8], offset: usize) -> Option<usize> {
+    let byte = input[offset];
+    if byte != b'`' && byte != b'~' {
+      return None;
+    }
+
+    let length = run_length(input, offset, byte);
+    if let Some(end) = fenced_block_end(input, offset) {
+      self.code_end = end;
+    } else if byte == b'~' {
+      return None;
+    } else if !self.unclosed_code_spans.contains(&length) {
+      // Without a closing run the backticks are literal.
+      match code_span_end(input, offset + length, length) {
+        Some(end) => self.code_end = end,
+        None => self.unclosed_code_spans.push(length),
+      }
+    }
+
+    Some(length)
+  }
+
+  fn open(&mut self, start: Position, delimiter: usize) {
+    if self.can_open() {
+      self.stack.push((start, delimiter));
+    } else {
+      self.report(DiagnosticKind::NestingTooDeep, start, delimiter, self.delimiters[delimiter].open.len());
+    }
+  }
+
+  fn close(&mut self, position: Position, delimiter: usize, length: usize) {
+    match self.stack.last() {
+      Some(&(start, open)) if open == delimiter => {
+        self.stack.pop();
+        self.ranges.push(Range { start, end: sequence_range(position, length).end });
+        self.depths.push(self.stack.len());
+        self.kinds.push(delimiter);
+      }
+      _ => self.report(DiagnosticKind::UnexpectedClosingBracket, position, delimiter, length),
+    }
+  }
+
+  fn report(&mut self, kind: DiagnosticKind, position: Position, delimiter: usize, length: usize) {
+    self.diagnostics.push(Diagnostic { kind, range: sequence_range(position, length), delimiter });
+  }
+
+  /// Reports brackets opened before the line as unclosed.
+  fn close_lines_before(&mut self, line: usize) {
+    let count = self.stack.iter().take_while(|(start, _)| start.line < line).count();
+    let rest = self.stack.split_off(count);
+    for (start, delimiter) in std::mem::replace(&mut self.stack, rest) {
+      self.report(DiagnosticKind::UnclosedBracket, start, delimiter, self.delimiters[delimiter].open.len());
+    }
+  }
+
+  /// Reports brackets that are still open. Called once the whole input is parsed.
+  pub fn finish(&mut self) {
+    let stack = std::mem::take(&mut self.stack);
+    for (start, delimiter) in stack {
+      self.report(DiagnosticKind::UnclosedBracket, start, delimiter, self.delimiters[delimiter].open.len());
+    }
+  }
+
+  /// Closed ranges in the order of their closing brackets (inner ranges come before outer ones).
+  #[must_use]
+  pub fn ranges(&self) -> &[Range] {
+    &self.ranges
+  }
+
+  /// Nesting depth of every range (0 for top-level ranges), indexed like `ranges()`.
+  #[must_use]
+  pub fn depths(&self) -> &[usize] {
+    &self.depths
+  }
+
+  /// Index of the delimiter of every range, indexed like `ranges()`.
+  #[must_use]
+  pub fn kinds(&self) -> &[usize] {
+    &self.kinds
+  }
+
+  /// Unmatched brackets in the order they were encountered, followed by the ones left unclosed. In single-line mode,
+  /// brackets left open at the end of a line are reported as soon as a delimiter on a later line is handled.
+  #[must_use]
+  pub fn diagnostics(&self) -> &[Diagnostic] {
+    &self.diagnostics
+  }
+
+  #[must_use]
+  pub fn tree(&self) -> RangeTree {
+    RangeTree::new(&self.ranges)
+  }
+}
+
+/// Range of a delimiter sequence. Sequences are ASCII, so every byte is a single column in every encoding.
+const fn sequence_range(start: Position, length: usize) -> Range {
+  Range {
+    start,
+    end: Position { line: start.line, character: start.character + length, offset: start.offset + length },
+  }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RangeNode {
+  pub range: Range,
+  pub depth: usize,
+  pub parent: Option<usize>,
+  pub children: Vec<usize>,
+}
+
+/// Ranges arranged by nesting. Nodes are stored in the order of their opening brackets.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct RangeTree {
+  pub nodes: Vec<RangeNode>,
+  pub roots: Vec<usize>,
+}
+
+impl RangeTree {
+  /// Builds a tree out of properly nested (or disjoint) ranges given in any order.
+  #[must_use]
+  pub fn new(ranges: &[Range]) -> Self {
+    let mut sorted = ranges.to_vec();
+    sorted.sort_by_key(|range| (range.start.offset, std::cmp::Reverse(range.end.offset)));
+
+    let mut tree = Self::default();
+    let mut ancestors: Vec<usize> = vec![];
+
+    for range in sorted {
+      while let Some(&last) = ancestors.last() {
+        if range.end.offset <= tree.nodes[last].range.end.offset {
+          break;
+        }
+        ancestors.pop();
+      }
+
+      let index = tree.nodes.len();
+      let parent = ancestors.last().copied();
+      tree.nodes.push(RangeNode { range, depth: ancestors.len(), parent, children: vec![] });
+
+      match parent {
+        Some(parent) => tree.nodes[parent].children.push(index),
+        None => tree.roots.push(index),
+      }
+      ancestors.push(index);
+    }
+
+    tree
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn range(start: usize, end: usize) -> Range {
+    Range {
+      start: Position { line: 0, character: start, offset: start },
+      end: Position { line: 0, character: end, offset: end },
+    }
+  }
+
+  fn feed(brackets: &mut Brackets, input: &str) {
+    let mut offset = 0;
+    while offset < input.len() {
+      let length = brackets.handle(input.as_bytes(), Position { line: 0, character: offset, offset });
+      offset += length.max(1);
+    }
+    brackets.finish();
+  }
+
+  #[test]
+  pub fn nesting_test() {
+    let mut brackets = Brackets::new(ParseOptions { max_depth: 3, ..ParseOptions::default() });
+    feed(&mut brackets, "[a [b] [c [d]]]");
+
+    assert_eq!(brackets.ranges(), [range(3, 6), range(10, 13), range(7, 14), range(0, 15)]);
+    assert_eq!(brackets.depths(), [1, 2, 1, 0]);
+
+    let tree = brackets.tree();
+    assert_eq!(tree.roots, [0]);
+    assert_eq!(tree.nodes[0].children, [1, 2]);
+    assert_eq!(tree.nodes[2].children, [3]);
+    assert_eq!(tree.nodes[3].parent, Some(2));
+    assert_eq!(tree.nodes.iter().map(|node| node.depth).collect::<Vec<_>>(), [0, 1, 1, 2]);
+  }
+
+  #[test]
+  pub fn max_depth_test() {
+    // With nesting disabled only "[a [b]" is a range.
+    let mut brackets = Brackets::new(ParseOptions { max_depth: 1, ..ParseOptions::default() });
+    feed(&mut brackets, "[a [b]");
+    assert!(!brackets.can_close());
+    feed(&mut brackets, " c]");
+
+    assert_eq!(brackets.ranges(), [range(0, 6)]);
+    assert_eq!(brackets.depths(), [0]);
+  }
+
+  #[test]
+  pub fn diagnostics_test() {
+    let mut brackets = Brackets::new(ParseOptions { max_depth: 2, ..ParseOptions::default() });
+    feed(&mut brackets, "] [a [b [c] [d");
+
+    assert_eq!(brackets.ranges(), [range(5, 11)]);
+    assert_eq!(
+      brackets.diagnostics(),
+      [
+        Diagnostic { kind: DiagnosticKind::UnexpectedClosingBracket, range: range(0, 1), delimiter: 0 },
+        Diagnostic { kind: DiagnosticKind::NestingTooDeep, range: range(8, 9), delimiter: 0 },
+        Diagnostic { kind: DiagnosticKind::UnclosedBracket, range: range(2, 3), delimiter: 0 },
+        Diagnostic { kind: DiagnosticKind::UnclosedBracket, range: range(12, 13), delimiter: 0 },
+      ]
+    );
+
+    brackets.finish();
+    assert_eq!(brackets.diagnostics().len(), 4);
+  }
+
+  #[test]
+  pub fn delimiters_test() {
+    let delimiters = vec![Delimiter::new(b'(', b')'), Delimiter::new(b'{', b'}'), Delimiter::new(b'$', b'$')];
+    let mut brackets = Brackets::new(ParseOptions { delimiters, max_depth: 4, ..ParseOptions::default() });
+    feed(&mut brackets, "({$a$}) ($b$) {c)");
+
+    assert_eq!(brackets.ranges(), [range(2, 5), range(1, 6), range(0, 7), range(9, 12), range(8, 13)]);
+    assert_eq!(brackets.kinds(), [2, 1, 0, 2, 0]);
+    assert_eq!(
+      brackets.diagnostics(),
+      [
+        Diagnostic { kind: DiagnosticKind::UnexpectedClosingBracket, range: range(16, 17), delimiter: 0 },
+        Diagnostic { kind: DiagnosticKind::UnclosedBracket, range: range(14, 15), delimiter: 1 },
+      ]
+    );
+  }
+
+  #[test]
+  pub fn sequences_test() {
+    let delimiters = vec![
+      Delimiter::new(b'[', b']'),
+      Delimiter::sequence(b"[[", b"]]"),
+      Delimiter::sequence(b"$$", b"$$"),
+      Delimiter::new(b'$', b'$'),
+    ];
+    let mut brackets = Brackets::new(ParseOptions { delimiters, max_depth: 4, ..ParseOptions::default() });
+    feed(&mut brackets, "[[a]] [b]] $$c$$ $[d]$ [[e]");
+
+    assert_eq!(brackets.ranges(), [range(0, 5), range(6, 9), range(11, 16), range(18, 21), range(17, 22)]);
+    assert_eq!(brackets.kinds(), [1, 0, 2, 0, 3]);
+    assert_eq!(
+      brackets.diagnostics(),
+      [
+        Diagnostic { kind: DiagnosticKind::UnexpectedClosingBracket, range: range(9, 10), delimiter: 0 },
+        Diagnostic { kind: DiagnosticKind::UnexpectedClosingBracket, range: range(26, 27), delimiter: 0 },
+        Diagnostic { kind: DiagnosticKind::UnclosedBracket, range: range(23, 25), delimiter: 1 },
+      ]
+    );
+  }
+
+  #[test]
+  pub fn escapes_test() {
+    for (escapes, expected) in [(true, vec![range(7, 14)]), (false, vec![range(1, 5), range(7, 13)])] {
+      let mut brackets = Brackets::new(ParseOptions { escapes, ..ParseOptions::default() });
+      feed(&mut brackets, r"\[a\]\\[b\\\]]");
+      assert_eq!(brackets.ranges(), expected);
+    }
+  }
+
+  #[test]
+  pub fn code_test() {
+    let mut brackets = Brackets::new(ParseOptions { escapes: true, code: true, ..ParseOptions::default() });
+    feed(&mut brackets, "[a] `[b]` ``[c` ]`` \\`[d] ` [e]\n```\n[f]\n```\n[g]");
+
+    assert_eq!(brackets.ranges(), [range(0, 3), range(22, 25), range(28, 31), range(44, 47)]);
+    assert_eq!(brackets.diagnostics(), []);
+  }
+
+  #[test]
+  #[should_panic(expected = "delimiters can't be empty")]
+  pub fn empty_delimiter_test() {
+    let _ = Delimiter::sequence(b"", b"]");
+  }
+
+  #[test]
+  pub fn tree_siblings_test() {
+    let tree = RangeTree::new(&[range(5, 8), range(0, 3), range(10, 20), range(12, 14)]);
+
+    assert_eq!(tree.roots, [0, 1, 2]);
+    assert_eq!(tree.nodes[2].children, [3]);
+    assert_eq!(tree.nodes[3].range, range(12, 14));
+  }
+}
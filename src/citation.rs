@@ -0,0 +1,359 @@
+//! Pandoc citations (`[see @key, p. 14; -@other]`), see pandoc's `citations` extension.
+
+use crate::inline::*;
+use crate::parser::*;
+use crate::types::*;
+use crate::utf16_parser::*;
+use crate::utf32_parser::*;
+use crate::utf8_parser::*;
+
+/// Single item of a citation.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Cite {
+  /// The whole item, without surrounding whitespace and separators.
+  pub range: Range,
+  /// Text before the key, e.g. `see` in `[see @key]`.
+  pub prefix: Option<Range>,
+  /// Citation key without `@` (and without braces in `@{key}`).
+  pub key: Range,
+  /// Whether the key is preceded by `-` (`[-@key]`), which suppresses the author name.
+  pub suppress_author: bool,
+  /// Locator such as `p. 14`, `chap. 2` or a bare `14`.
+  pub locator: Option<Range>,
+  /// Text after the locator (or after the key if there's no locator).
+  pub suffix: Option<Range>,
+}
+
+/// Bracketed citation that consists of one or more items separated by `;`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Citation {
+  /// Range of the brackets.
+  pub range: Range,
+  pub cites: Vec<Cite>,
+}
+
+/// Terms that may precede a locator number (see pandoc's `Text.Pandoc.Citeproc.Locator`).
+pub const LOCATOR_TERMS: [&str; 50] = [
+  "book",
+  "books",
+  "bk.",
+  "bks.",
+  "chapter",
+  "chapters",
+  "chap.",
+  "chaps.",
+  "column",
+  "columns",
+  "col.",
+  "cols.",
+  "figure",
+  "figures",
+  "fig.",
+  "figs.",
+  "folio",
+  "folios",
+  "fol.",
+  "fols.",
+  "line",
+  "lines",
+  "l.",
+  "ll.",
+  "note",
+  "notes",
+  "n.",
+  "nn.",
+  "number",
+  "numbers",
+  "no.",
+  "nos.",
+  "opus",
+  "op.",
+  "page",
+  "pages",
+  "p.",
+  "pp.",
+  "paragraph",
+  "para.",
+  "part",
+  "pt.",
+  "section",
+  "sec.",
+  "verse",
+  "v.",
+  "volume",
+  "vol.",
+  "§",
+  "¶",
+];
+
+/// Finds citations with the strategy and parses their items.
+#[must_use]
+pub fn parse_citations<'a, P: RangeParser<'a>>(input: &'a str, strategy: Strategy) -> Vec<Citation> {
+  parse_inlines::<P>(input, strategy)
+    .into_iter()
+    .filter(|inline| inline.kind == InlineKind::Citation)
+    .filter_map(|inline| parse_citation(input, inline.range, P::ENCODING))
+    .collect()
+}
+
+/// Same as [`parse_citations`], with the parser picked by the encoding.
+#[must_use]
+pub fn parse_citations_with_encoding(input: &str, encoding: Encoding, strategy: Strategy) -> Vec<Citation> {
+  match encoding {
+    Encoding::Utf8 => parse_citations::<Utf8Parser>(input, strategy),
+    Encoding::Utf16 => parse_citations::<Utf16Parser>(input, strategy),
+    Encoding::Utf32 => parse_citations::<Utf32Parser>(input, strategy),
+  }
+}
+
+/// Parses a bracket range (including brackets) as a citation. Returns `None` if any of its items lacks a key.
+#[must_use]
+pub fn parse_citation(input: &str, range: Range, encoding: Encoding) -> Option<Citation> {
+  let bytes = input.as_bytes();
+  let start = range.start.offset + 1;
+  let end = range.end.offset - 1;
+
+  // Offsets of the parts are increasing, so positions are computed in a single pass.
+  let mut cursor = Position { line: range.start.line, character: range.start.character + 1, offset: start };
+  let mut position = |offset| {
+    cursor = encoding.advance(input, cursor, offset);
+    cursor
+  };
+
+  let mut cites = vec![];
+  let mut item_start = start;
+  while item_start < end {
+    let item_end = find_separator(bytes, item_start, end);
+    let item = parse_cite(bytes, item_start, item_end)?;
+
+    let cite_start = position(item.range.0);
+    let prefix = item.prefix.map(|(start, end)| Range { start: position(start), end: position(end) });
+    let key = Range { start: position(item.key.0), end: position(item.key.1) };
+    let locator = item.locator.map(|(start, end)| Range { start: position(start), end: position(end) });
+    let suffix = item.suffix.map(|(start, end)| Range { start: position(start), end: position(end) });
+    let cite_end = position(item.range.1);
+
+    let range = Range { start: cite_start, end: cite_end };
+    cites.push(Cite { range, prefix, key, suppress_author: item.suppress_author, locator, suffix });
+    item_start = item_end + 1;
+  }
+
+  (!cites.is_empty()).then_some(Citation { range, cites })
+}
+
+/// Byte offsets of the parts of a citation item.
+struct CiteOffsets {
+  range: (usize, usize),
+  prefix: Option<(usize, usize)>,
+  key: (usize, usize),
+  suppress_author: bool,
+  locator: Option<(usize, usize)>,
+  suffix: Option<(usize, usize)>,
+}
+
+/// Offset of the next `;` that isn't inside braces, or `end`.
+fn find_separator(bytes: &[u8], start: usize, end: usize) -> usize {
+  let mut depth = 0usize;
+  for (i, &byte) in bytes.iter().enumerate().take(end).skip(start) {
+    match byte {
+      b'{' => depth += 1,
+      b'}' => depth = depth.saturating_sub(1),
+      b';' if depth == 0 => return i,
+      _ => {}
+    }
+  }
+  end
+}
+
+fn parse_cite(bytes: &[u8], start: usize, end: usize) -> Option<CiteOffsets> {
+  let range = trim(bytes, start, end);
+
+  let at = (range.0..range.1).find(|&i| {
+    let before = if i > range.0 && bytes[i - 1] == b'-' { i - 1 } else { i };
+    bytes[i] == b'@' && (before == range.0 || bytes[before - 1].is_ascii_whitespace())
+  })?;
+  let suppress_author = at > range.0 && bytes[at - 1] == b'-';
+  let prefix = non_empty(trim(bytes, range.0, if suppress_author { at - 1 } else { at }));
+
+  let key = parse_key(bytes, at + 1, range.1)?;
+  let key_end = if bytes[key.0 - 1] == b'{' { key.1 + 1 } else { key.1 };
+
+  let mut rest = trim(bytes, key_end, range.1).0;
+  if rest < range.1 && bytes[rest] == b',' {
+    rest = trim(bytes, rest + 1, range.1).0;
+  }
+  let locator = parse_locator(bytes, rest, range.1);
+  let suffix_start = match locator {
+    Some((start, end)) if bytes[start - 1] == b'{' => end + 1,
+    Some((_, end)) => end,
+    None => key_end,
+  };
+  let suffix = non_empty(trim(bytes, suffix_start, range.1));
+
+  Some(CiteOffsets { range, prefix, key, suppress_author, locator, suffix })
+}
+
+/// Parses a key (either `key` or `{key}`) at `start`. Keys start with an alphanumeric character or `_` and may contain
+/// punctuation in the middle.
+fn parse_key(bytes: &[u8], start: usize, end: usize) -> Option<(usize, usize)> {
+  if start < end && bytes[start] == b'{' {
+    let length = bytes[start + 1..end].iter().position(|&byte| byte == b'}')?;
+    return non_empty((start + 1, start + 1 + length));
+  }
+
+  let is_key_char = |byte: u8| byte.is_ascii_alphanumeric() || byte == b'_' || byte >= 0x80;
+  let is_internal = |byte: u8| b":.#$%&-+?<>~/".contains(&byte);
+
+  let mut key_end = start;
+  let mut i = start;
+  while i < end && (is_key_char(bytes[i]) || i > start && is_internal(bytes[i])) {
+    if is_key_char(bytes[i]) {
+      key_end = i + 1;
+    }
+    i += 1;
+  }
+  non_empty((start, key_end))
+}
+
+/// Parses a locator (`{anything}`, or an optional term followed by numbers such as `14`, `33-35` or `p. iv, 2`). Roman
+/// numerals are only recognized after a term, so that ordinary words such as `mix` aren't mistaken for them.
+fn parse_locator(bytes: &[u8], start: usize, end: usize) -> Option<(usize, usize)> {
+  if start < end && bytes[start] == b'{' {
+    let length = bytes[start + 1..end].iter().position(|&byte| byte == b'}')?;
+    return Some((start + 1, start + 1 + length));
+  }
+
+  let text = &bytes[start..end];
+  let mut value_start = start;
+  for term in LOCATOR_TERMS {
+    let term = term.as_bytes();
+    let is_word_end = matches!(text.get(term.len()), Some(byte) if byte.is_ascii_whitespace() || byte.is_ascii_digit());
+    if text.starts_with(term) && is_word_end {
+      value_start = trim(bytes, start + term.len(), end).0;
+      break;
+    }
+  }
+
+  let roman = value_start > start;
+  let is_value = |byte: u8| byte.is_ascii_digit() || byte == b'-' || roman && b"ivxlcdm".contains(&byte);
+  let mut value_end = value_start;
+  loop {
+    let length = bytes[value_end..end].iter().take_while(|&&byte| is_value(byte)).count();
+    if !bytes[value_end..value_end + length].iter().any(|&byte| byte != b'-') {
+      break;
+    }
+    value_end += length;
+
+    // Lists such as `14, 16`.
+    if value_end + 1 < end && bytes[value_end] == b',' {
+      let next = trim(bytes, value_end + 1, end).0;
+      if next < end && bytes[next].is_ascii_digit() {
+        value_end = next;
+        continue;
+      }
+    }
+    break;
+  }
+
+  let is_word_end = value_end == end || !bytes[value_end].is_ascii_alphanumeric();
+  (value_end > value_start && is_word_end).then_some((start, value_end))
+}
+
+/// Range without leading and trailing whitespace.
+fn trim(bytes: &[u8], mut start: usize, mut end: usize) -> (usize, usize) {
+  while start < end && bytes[start].is_ascii_whitespace() {
+    start += 1;
+  }
+  while end > start && bytes[end - 1].is_ascii_whitespace() {
+    end -= 1;
+  }
+  (start, end)
+}
+
+const fn non_empty(range: (usize, usize)) -> Option<(usize, usize)> {
+  if range.0 < range.1 {
+    Some(range)
+  } else {
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tests::test_data::*;
+
+  type Parts<'a> = (Option<&'a str>, &'a str, bool, Option<&'a str>, Option<&'a str>);
+
+  fn parts<'a>(input: &'a str, citation: &Citation) -> Vec<Parts<'a>> {
+    let text = |range: Range| &input[range.start.offset..range.end.offset];
+    citation
+      .cites
+      .iter()
+      .map(|cite| {
+        (cite.prefix.map(text), text(cite.key), cite.suppress_author, cite.locator.map(text), cite.suffix.map(text))
+      })
+      .collect()
+  }
+
+  fn assert_citations<'a, P: RangeParser<'a>>(input: &'a str, expected: &[Vec<Parts>]) {
+    for strategy in Strategy::ALL {
+      let citations = parse_citations::<P>(input, strategy);
+      let actual: Vec<_> = citations.iter().map(|citation| parts(input, citation)).collect();
+      assert_eq!(actual, expected, "{}", strategy.name());
+    }
+  }
+
+  #[test]
+  pub fn parse_citations_test() {
+    let input = "[see @doe99, pp. 33-35, 38; also -@smith04, chap. 1 and elsewhere] [@{a b}, {iv}] [-@d 1] [@e 14a] \
+      [email@example.com] [@f, p. ii] [@g, ii] [@doe99, civil law] [@doe99, did so] [@doe99, mix]";
+    let expected = [
+      vec![
+        (Some("see"), "doe99", false, Some("pp. 33-35, 38"), None),
+        (Some("also"), "smith04", true, Some("chap. 1"), Some("and elsewhere")),
+      ],
+      vec![(None, "a b", false, Some("iv"), None)],
+      vec![(None, "d", true, Some("1"), None)],
+      vec![(None, "e", false, None, Some("14a"))],
+      vec![(None, "f", false, Some("p. ii"), None)],
+      vec![(None, "g", false, None, Some(", ii"))],
+      vec![(None, "doe99", false, None, Some(", civil law"))],
+      vec![(None, "doe99", false, None, Some(", did so"))],
+      vec![(None, "doe99", false, None, Some(", mix"))],
+    ];
+
+    assert_citations::<Utf32Parser>(input, &expected);
+    assert_citations::<Utf16Parser>(input, &expected);
+    assert_citations::<Utf8Parser>(input, &expected);
+  }
+
+  #[test]
+  pub fn parse_citations_test_data_test() {
+    let citations = parse_citations::<Utf16Parser>(LONG_MULTILINE_INPUT, Strategy::Vector256);
+    let locators: Vec<_> = citations
+      .iter()
+      .map(|citation| {
+        let parts = parts(LONG_MULTILINE_INPUT, citation);
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].1, "grune_parsingtechniques_en_1990");
+        parts[0].3.unwrap()
+      })
+      .collect();
+    assert_eq!(locators, ["1", "13", "14", "19", "23"]);
+  }
+
+  #[test]
+  pub fn parse_citations_characters_test() {
+    let input = "😀 [😀 @ключ, p. 5]";
+    let characters = |citation: &Citation| {
+      let cite = citation.cites[0];
+      let (key, locator) = (cite.key, cite.locator.unwrap());
+      (key.start.character, key.end.character, locator.start.character, locator.end.character)
+    };
+
+    assert_eq!(characters(&parse_citations::<Utf32Parser>(input, Strategy::Vector128)[0]), (6, 10, 12, 16));
+    assert_eq!(characters(&parse_citations::<Utf16Parser>(input, Strategy::Vector128)[0]), (8, 12, 14, 18));
+    assert_eq!(characters(&parse_citations::<Utf8Parser>(input, Strategy::Vector128)[0]), (12, 20, 22, 26));
+  }
+}
@@ -0,0 +1,106 @@
+//! Markdown code spans and fenced code blocks, whose contents are never parsed for delimiters.
+
+/// Length of the run of `byte`s that starts at `offset`.
+#[inline]
+#[must_use]
+pub fn run_length(bytes: &[u8], offset: usize, byte: u8) -> usize {
+  bytes[offset..].iter().take_while(|&&b| b == byte).count()
+}
+
+/// Checks whether `offset` is preceded only by up to 3 spaces on its line, which is where fences may start.
+#[must_use]
+pub fn is_fence_indentation(bytes: &[u8], offset: usize) -> bool {
+  let spaces = bytes[..offset].iter().rev().take_while(|&&byte| byte == b' ').count();
+  spaces <= 3 && (spaces == offset || is_line_ending(bytes[offset - spaces - 1]))
+}
+
+/// If a fence (3 or more backticks or tildes) starts at `offset`, returns the offset right after the closing fence, or
+/// the input length if the block is never closed.
+#[must_use]
+pub fn fenced_block_end(bytes: &[u8], offset: usize) -> Option<usize> {
+  let fence = bytes[offset];
+  let length = run_length(bytes, offset, fence);
+  if !matches!(fence, b'`' | b'~') || length < 3 || !is_fence_indentation(bytes, offset) {
+    return None;
+  }
+
+  let info_end = line_end(bytes, offset);
+  if fence == b'`' && bytes[offset + length..info_end].contains(&b'`') {
+    return None;
+  }
+
+  let mut line_start = next_line_start(bytes, info_end);
+  while line_start < bytes.len() {
+    let end = line_end(bytes, line_start);
+    let indentation = bytes[line_start..end].iter().take_while(|&&byte| byte == b' ').count();
+    let fence_start = line_start + indentation;
+
+    if indentation <= 3 && fence_start < end {
+      let closing_length = run_length(bytes, fence_start, fence);
+      let fence_end = fence_start + closing_length;
+      if closing_length >= length && bytes[fence_end..end].iter().all(|&byte| byte == b' ' || byte == b'\t') {
+        return Some(fence_end);
+      }
+    }
+
+    line_start = next_line_start(bytes, end);
+  }
+
+  Some(bytes.len())
+}
+
+/// Finds the closing run of a code span that is opened by a run of `length` backticks ending at `offset`. Returns the
+/// offset right after it.
+#[must_use]
+pub fn code_span_end(bytes: &[u8], mut offset: usize, length: usize) -> Option<usize> {
+  while let Some(start) = bytes[offset..].iter().position(|&byte| byte == b'`') {
+    let start = offset + start;
+    let run = run_length(bytes, start, b'`');
+    if run == length {
+      return Some(start + run);
+    }
+    offset = start + run;
+  }
+
+  None
+}
+
+#[inline]
+const fn is_line_ending(byte: u8) -> bool {
+  byte == b'\n' || byte == b'\r'
+}
+
+fn line_end(bytes: &[u8], offset: usize) -> usize {
+  bytes[offset..].iter().position(|&byte| is_line_ending(byte)).map_or(bytes.len(), |end| offset + end)
+}
+
+fn next_line_start(bytes: &[u8], line_end: usize) -> usize {
+  if bytes.get(line_end) == Some(&b'\r') && bytes.get(line_end + 1) == Some(&b'\n') {
+    line_end + 2
+  } else {
+    line_end + 1
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  pub fn fenced_block_end_test() {
+    let input = b"```rust\n[a]\n  ```` \n[b]";
+    assert_eq!(fenced_block_end(input, 0), Some(18));
+    assert_eq!(fenced_block_end(input, 14), Some(input.len()));
+
+    assert_eq!(fenced_block_end(b"~~~\r\n[a]\r\n~~", 0), Some(12));
+    assert_eq!(fenced_block_end(b"``` a`b\n[a]\n```", 0), None);
+    assert_eq!(fenced_block_end(b"a ```\n[a]\n```", 2), None);
+  }
+
+  #[test]
+  pub fn code_span_end_test() {
+    let input = b"``a ` ``` b`` c";
+    assert_eq!(code_span_end(input, 2, 2), Some(13));
+    assert_eq!(code_span_end(input, 5, 1), None);
+  }
+}
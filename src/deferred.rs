@@ -0,0 +1,260 @@
+//! Deferred position resolution: brackets are matched by byte offsets only, and lines and characters are resolved
+//! afterwards in a single pass over the sorted offsets, in whatever encoding is needed.
+
+use std::arch::x86_64::*;
+
+use crate::brackets::*;
+use crate::lookup::*;
+use crate::parser::*;
+use crate::types::*;
+use crate::utf8::*;
+
+/// Continuation bytes are neither line endings nor character starts, so they pad the last block.
+const PADDING: u8 = 0b1000_0000;
+
+/// Parser that only tracks byte offsets, so that one scan of the input serves every encoding.
+pub struct OffsetParser<'a> {
+  input: &'a str,
+  brackets: Brackets,
+  lookup: Lookup,
+  /// Strategy of the last parse, which also picks how positions are resolved.
+  strategy: Strategy,
+}
+
+impl<'a> OffsetParser<'a> {
+  #[must_use]
+  pub fn with_options(input: &'a str, options: ParseOptions) -> Self {
+    // Line endings don't matter until positions are resolved, unless brackets are closed at the end of every line.
+    let line_endings = if options.single_line { &b"\n\r"[..] } else { &[] };
+    let brackets = Brackets::new(options);
+    let lookup = Lookup::new(brackets.leading_bytes().chain(line_endings.iter().copied()));
+    Self { input, brackets, lookup, strategy: Strategy::Chars }
+  }
+
+  #[must_use]
+  pub fn new(input: &'a str) -> Self {
+    Self::with_options(input, ParseOptions::default())
+  }
+
+  /// Matches brackets, finding delimiters with the strategy. Positions of the matched brackets only have their
+  /// offsets (and lines, in single-line mode) set until they are resolved with [`Self::ranges`] or
+  /// [`Self::diagnostics`].
+  pub fn parse(&mut self, strategy: Strategy) -> &Brackets {
+    let bytes = self.input.as_bytes();
+    self.strategy = strategy;
+    // Offset up to which bytes belong to an already handled delimiter sequence.
+    let mut skip_until = 0;
+    let mut line = 0;
+
+    for offset in self.lookup.find_all(self.input, strategy) {
+      match bytes[offset] {
+        b'\r' => line += 1,
+        b'\n' if offset == 0 || bytes[offset - 1] != b'\r' => line += 1,
+        b'\n' => {}
+        _ if offset >= skip_until => {
+          skip_until = offset + self.brackets.handle(bytes, Position { line, offset, ..Position::default() });
+        }
+        _ => {}
+      }
+    }
+
+    self.brackets.finish();
+    &self.brackets
+  }
+
+  /// Ranges matched by [`Self::parse`] with positions in the encoding.
+  #[must_use]
+  pub fn ranges(&self, encoding: Encoding) -> Vec<Range> {
+    let resolve = Resolved::new(self.input, self.brackets.ranges().iter().copied(), encoding, self.strategy);
+    self.brackets.ranges().iter().map(|&range| resolve.range(range)).collect()
+  }
+
+  /// Diagnostics reported by [`Self::parse`] with positions in the encoding.
+  #[must_use]
+  pub fn diagnostics(&self, encoding: Encoding) -> Vec<Diagnostic> {
+    let diagnostics = self.brackets.diagnostics();
+    let ranges = diagnostics.iter().map(|diagnostic| diagnostic.range);
+    let resolve = Resolved::new(self.input, ranges, encoding, self.strategy);
+    diagnostics.iter().map(|&diagnostic| Diagnostic { range: resolve.range(diagnostic.range), ..diagnostic }).collect()
+  }
+}
+
+/// Positions of the bounds of a set of ranges that only have their offsets set.
+pub(crate) struct Resolved {
+  offsets: Vec<usize>,
+  positions: Vec<Position>,
+}
+
+impl Resolved {
+  pub(crate) fn new(input: &str, ranges: impl Iterator<Item = Range>, encoding: Encoding, strategy: Strategy) -> Self {
+    let mut offsets: Vec<_> = ranges.flat_map(|range| [range.start.offset, range.end.offset]).collect();
+    offsets.sort_unstable();
+    offsets.dedup();
+    let positions = resolve_positions(input, &offsets, encoding, strategy);
+    Self { offsets, positions }
+  }
+
+  pub(crate) fn range(&self, range: Range) -> Range {
+    let position = |offset| self.positions[self.offsets.partition_point(|&other| other < offset)];
+    Range { start: position(range.start.offset), end: position(range.end.offset) }
+  }
+}
+
+/// Turns sorted offsets of the input (which must be character boundaries) into positions in the encoding, counting
+/// line endings and characters between consecutive offsets. 256-bit strategies count them in 32-byte blocks with AVX2,
+/// and the others go character by character.
+#[must_use]
+pub fn resolve_positions(input: &str, offsets: &[usize], encoding: Encoding, strategy: Strategy) -> Vec<Position> {
+  let bytes = input.as_bytes();
+  let mut position = Position::default();
+  let avx2 = matches!(strategy, Strategy::Vector256 | Strategy::Bitmask256 | Strategy::Structural);
+
+  offsets
+    .iter()
+    .map(|&offset| {
+      position =
+        if avx2 { advance_v256(bytes, position, offset, encoding) } else { encoding.advance(input, position, offset) };
+      position
+    })
+    .collect()
+}
+
+/// Moves `position` forward to `offset`.
+fn advance_v256(bytes: &[u8], mut position: Position, offset: usize, encoding: Encoding) -> Position {
+  let mut block = position.offset;
+
+  while block < offset {
+    let mut padded = [PADDING; 32];
+    let v = if block + 32 <= bytes.len() {
+      unsafe { _mm256_loadu_si256(bytes[block..].as_ptr().cast()) }
+    } else {
+      padded[..bytes.len() - block].copy_from_slice(&bytes[block..]);
+      unsafe { _mm256_loadu_si256(padded.as_ptr().cast()) }
+    };
+    let mask = |byte: u8| unsafe { _mm256_movemask_epi8(_mm256_cmpeq_epi8(v, _mm256_set1_epi8(byte as i8))) as u32 };
+
+    let bits = bits_between(0, (offset - block).min(32));
+    let (carriage_returns, line_feeds) = (mask(b'\r') & bits, mask(b'\n') & bits);
+    let preceded_by_carriage_return = carriage_returns << 1 | u32::from(block > 0 && bytes[block - 1] == b'\r');
+    // The line feed of `\r\n` doesn't start another line, but it does reset the character.
+    let line_endings = carriage_returns | line_feeds;
+    position.line += (carriage_returns | line_feeds & !preceded_by_carriage_return).count_ones() as usize;
+
+    let units = |bits: u32| {
+      let units = match encoding {
+        Encoding::Utf8 => bits.count_ones(),
+        Encoding::Utf16 => {
+          (character_starts_v256(v) & bits).count_ones() + (four_byte_starts_v256(v) & bits).count_ones()
+        }
+        Encoding::Utf32 => (character_starts_v256(v) & bits).count_ones(),
+      };
+      units as usize
+    };
+    if line_endings == 0 {
+      position.character += units(bits);
+    } else {
+      let last_line_ending = 31 - line_endings.leading_zeros() as usize;
+      position.character = units(bits & !bits_between(0, last_line_ending + 1));
+    }
+
+    block += 32;
+  }
+
+  position.offset = offset;
+  position
+}
+
+#[cfg(test)]
+pub mod tests {
+  extern crate test;
+  use test::bench::Bencher;
+
+  use proptest::prelude::*;
+
+  use super::*;
+  use crate::parser::Strategy;
+  use crate::tests::test_data::*;
+  use crate::utf16_parser::*;
+  use crate::utf32_parser::*;
+  use crate::utf8_parser::*;
+
+  fn assert_resolved<'a, T: RangeParser<'a>>(input: &'a str, options: &ParseOptions) {
+    let mut reference = T::with_options(input, options.clone());
+    let expected = reference.parse_chars().to_vec();
+
+    for strategy in Strategy::ALL {
+      let mut parser = OffsetParser::with_options(input, options.clone());
+      parser.parse(strategy);
+      assert_eq!(parser.ranges(T::ENCODING), expected, "{}", strategy.name());
+      assert_eq!(parser.diagnostics(T::ENCODING), reference.brackets().diagnostics(), "{}", strategy.name());
+    }
+  }
+
+  proptest! {
+    #[test]
+    fn parse_deferred_property_test(
+      s in "[0-9a-zA-Zа-яА-Я😀 \\[\\]()$`~\\\\\\n\\r]{300}",
+      max_depth in 1..8usize,
+      escapes in any::<bool>(),
+      code in any::<bool>(),
+      single_line in any::<bool>(),
+    ) {
+      let delimiters = vec![Delimiter::new(b'[', b']'), Delimiter::new(b'(', b')'), Delimiter::new(b'$', b'$')];
+      let options = ParseOptions { delimiters, max_depth, escapes, code, single_line };
+      assert_resolved::<Utf8Parser>(&s, &options);
+      assert_resolved::<Utf16Parser>(&s, &options);
+      assert_resolved::<Utf32Parser>(&s, &options);
+    }
+  }
+
+  #[test]
+  pub fn resolve_positions_test() {
+    let input = format!("a\r\n{}\r\r\n😀\nбв", "-".repeat(30));
+    let offsets: Vec<_> = input.char_indices().map(|(offset, _)| offset).chain([input.len()]).collect();
+
+    for encoding in [Encoding::Utf8, Encoding::Utf16, Encoding::Utf32] {
+      let expected: Vec<_> =
+        offsets.iter().map(|&offset| encoding.advance(&input, Position::default(), offset)).collect();
+      for strategy in Strategy::ALL {
+        assert_eq!(resolve_positions(&input, &offsets, encoding, strategy), expected, "{}", strategy.name());
+      }
+    }
+  }
+
+  #[test]
+  pub fn parse_deferred_test_data_test() {
+    for input in [SHORT_MULTILINE_INPUT, LONG_MULTILINE_INPUT, DENSE_BRACKETS_INPUT, MIXED_LINE_ENDINGS_INPUT] {
+      let options = ParseOptions::default();
+      assert_resolved::<Utf8Parser>(input, &options);
+      assert_resolved::<Utf16Parser>(input, &options);
+      assert_resolved::<Utf32Parser>(input, &options);
+    }
+  }
+
+  #[bench]
+  pub fn parse_deferred_utf16_bench(b: &mut Bencher) {
+    b.iter(|| {
+      let mut parser = OffsetParser::new(BENCHMARK_INPUT);
+      parser.parse(Strategy::Bitmask256);
+      parser.ranges(Encoding::Utf16).len()
+    });
+  }
+
+  #[bench]
+  pub fn parse_deferred_utf32_bench(b: &mut Bencher) {
+    b.iter(|| {
+      let mut parser = OffsetParser::new(BENCHMARK_INPUT);
+      parser.parse(Strategy::Bitmask256);
+      parser.ranges(Encoding::Utf32).len()
+    });
+  }
+
+  #[bench]
+  pub fn parse_deferred_all_encodings_bench(b: &mut Bencher) {
+    b.iter(|| {
+      let mut parser = OffsetParser::new(BENCHMARK_INPUT);
+      parser.parse(Strategy::Bitmask256);
+      [Encoding::Utf8, Encoding::Utf16, Encoding::Utf32].map(|encoding| parser.ranges(encoding).len())
+    });
+  }
+}
@@ -0,0 +1,439 @@
+//! Parser that produces character-based offsets and UTF-16 offsets at the same time.
+//!
+//! UTF-16 column is the character column plus the number of 4-byte characters (the ones outside of the BMP, which take
+//! a surrogate pair) before it on the line, so the parser only has to count 4-byte lead bytes next to characters.
+
+use std::arch::x86_64::*;
+use std::simd::u8x16;
+
+use crate::brackets::*;
+use crate::lookup::*;
+use crate::parser::*;
+use crate::structural::*;
+use crate::types::*;
+use crate::utf8::*;
+
+/// Position with the column both in characters and in UTF-16 code units.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct DualPosition {
+  pub line: usize,
+  /// Column in characters (LSP `utf-32`).
+  pub character: usize,
+  /// Column in UTF-16 code units (LSP `utf-16`).
+  pub utf16_character: usize,
+  pub offset: usize,
+}
+
+impl DualPosition {
+  #[must_use]
+  pub const fn utf32(self) -> Position {
+    Position { line: self.line, character: self.character, offset: self.offset }
+  }
+
+  #[must_use]
+  pub const fn utf16(self) -> Position {
+    Position { line: self.line, character: self.utf16_character, offset: self.offset }
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DualRange {
+  pub start: DualPosition,
+  pub end: DualPosition,
+}
+
+impl DualRange {
+  #[must_use]
+  pub const fn utf32(self) -> Range {
+    Range { start: self.start.utf32(), end: self.end.utf32() }
+  }
+
+  #[must_use]
+  pub const fn utf16(self) -> Range {
+    Range { start: self.start.utf16(), end: self.end.utf16() }
+  }
+}
+
+/// Produces character-based ranges, like [`crate::utf32_parser::Utf32Parser`], and keeps track of 4-byte characters
+/// so that [`DualParser::dual_ranges`] can add UTF-16 columns to them.
+pub struct DualParser<'a> {
+  input: &'a str,
+  position: Position,
+  /// Number of 4-byte characters between the start of the line and `position`.
+  four_byte_characters: usize,
+  /// Offsets of handled delimiter sequences, with the number of 4-byte characters before them on their lines.
+  sequences: Vec<(usize, usize)>,
+  brackets: Brackets,
+  lookup: Lookup,
+}
+
+impl<'a> RangeParser<'a> for DualParser<'a> {
+  const ENCODING: Encoding = Encoding::Utf32;
+
+  fn with_options(input: &'a str, options: ParseOptions) -> Self {
+    let brackets = Brackets::new(options);
+    let lookup = Lookup::new(brackets.leading_bytes().chain(*b"\n\r"));
+    Self { input, position: Position::default(), four_byte_characters: 0, sequences: vec![], brackets, lookup }
+  }
+
+  fn brackets(&self) -> &Brackets {
+    &self.brackets
+  }
+
+  fn parse_chars(&mut self) -> &[Range] {
+    let mut previous_char = None;
+    // Characters that belong to a delimiter sequence that was already handled.
+    let mut skip = 0;
+
+    for char in self.input.chars() {
+      let previous_position = self.position;
+
+      self.position.character += 1;
+      self.position.offset += char.len_utf8();
+
+      match char {
+        '\r' => {
+          self.position.line += 1;
+          self.position.character = 0;
+          self.four_byte_characters = 0;
+        }
+        '\n' => {
+          if previous_char != Some('\r') {
+            self.position.line += 1;
+          }
+          self.position.character = 0;
+          self.four_byte_characters = 0;
+        }
+        _ if skip > 0 => skip -= 1,
+        _ if char.is_ascii() => skip = self.handle(previous_position).saturating_sub(1),
+        _ if char.len_utf16() == 2 => self.four_byte_characters += 1,
+        _ => {}
+      }
+
+      previous_char = Some(char);
+    }
+    self.brackets.finish();
+    self.brackets.ranges()
+  }
+
+  fn parse_bytes(&mut self) -> &[Range] {
+    self.parse_bytes_limited(self.input.len());
+    self.brackets.finish();
+    self.brackets.ranges()
+  }
+
+  fn parse_v128(&mut self) -> &[Range] {
+    let bytes = self.input.as_bytes();
+
+    while self.position.offset + 15 < bytes.len() {
+      let bytes_vec = unsafe { _mm_loadu_si128((bytes[self.position.offset..].as_ptr()).cast()) };
+
+      if self.lookup.hit_v128(bytes_vec) {
+        self.parse_bytes_limited(16);
+      } else {
+        self.position.character += count_utf8_characters_v128(bytes_vec);
+        self.four_byte_characters += count_four_byte_starts_v128(bytes_vec);
+        self.position.offset += 16;
+      }
+    }
+
+    self.parse_bytes();
+
+    self.brackets.ranges()
+  }
+
+  fn parse_v256(&mut self) -> &[Range] {
+    let bytes = self.input.as_bytes();
+
+    while self.position.offset + 31 < bytes.len() {
+      let bytes_vec = unsafe { _mm256_loadu_si256((bytes[self.position.offset..].as_ptr()).cast()) };
+
+      if self.lookup.hit_v256(bytes_vec) {
+        self.parse_bytes_limited(32);
+      } else {
+        self.position.character += count_utf8_characters_v256(bytes_vec);
+        self.four_byte_characters += four_byte_starts_v256(bytes_vec).count_ones() as usize;
+        self.position.offset += 32;
+      }
+    }
+
+    self.parse_bytes();
+
+    self.brackets.ranges()
+  }
+
+  fn parse_v128_portable(&mut self) -> &[Range] {
+    let bytes = self.input.as_bytes();
+
+    while self.position.offset + 15 < bytes.len() {
+      let bytes_vec = u8x16::from_slice(&bytes[self.position.offset..]);
+
+      if self.lookup.hit_v128_portable(bytes_vec) {
+        self.parse_bytes_limited(16);
+      } else {
+        self.position.character += count_utf8_characters_v128_portable(bytes_vec);
+        self.four_byte_characters += count_four_byte_starts_v128_portable(bytes_vec);
+        self.position.offset += 16;
+      }
+    }
+
+    self.parse_bytes();
+
+    self.brackets.ranges()
+  }
+
+  fn parse_bitmask(&mut self) -> &[Range] {
+    let bytes = self.input.as_bytes();
+
+    while self.position.offset + 31 < bytes.len() {
+      let block = self.position.offset;
+      let bytes_vec = unsafe { _mm256_loadu_si256((bytes[block..].as_ptr()).cast()) };
+      let starts = character_starts_v256(bytes_vec);
+      let four_byte_starts = four_byte_starts_v256(bytes_vec);
+
+      let mut hits = self.lookup.mask_v256(bytes_vec);
+      // Bytes of the block before `counted` are already counted.
+      let mut counted = 0;
+      while hits != 0 {
+        let hit = hits.trailing_zeros() as usize;
+        let (characters, four_byte_characters) = count_between(starts, four_byte_starts, counted, hit);
+        self.position.character += characters;
+        self.four_byte_characters += four_byte_characters;
+        self.position.offset = block + hit;
+        self.parse_bytes_limited(1);
+        counted = (self.position.offset - block).min(32);
+        hits &= !bits_between(0, counted);
+      }
+      let (characters, four_byte_characters) = count_between(starts, four_byte_starts, counted, 32);
+      self.position.character += characters;
+      self.four_byte_characters += four_byte_characters;
+      self.position.offset = self.position.offset.max(block + 32);
+    }
+
+    self.parse_bytes();
+
+    self.brackets.ranges()
+  }
+
+  fn parse_structural(&mut self) -> &[Range] {
+    let index = StructuralIndex::new(self.input, &self.lookup);
+
+    for &offset in index.offsets() {
+      let offset = offset as usize;
+      // Offsets before the current one belong to an already handled delimiter sequence.
+      if offset >= self.position.offset {
+        let characters = index.characters(offset) - index.characters(self.position.offset);
+        let utf16_units = index.utf16_units(offset) - index.utf16_units(self.position.offset);
+        self.position.character += characters;
+        self.four_byte_characters += utf16_units - characters;
+        self.position.offset = offset;
+        self.parse_bytes_limited(1);
+      }
+    }
+
+    self.brackets.finish();
+    self.brackets.ranges()
+  }
+}
+
+impl<'a> DualParser<'a> {
+  pub fn parse_bytes_limited(&mut self, limit: usize) {
+    let bytes = self.input.as_bytes();
+
+    let max_offset = (self.position.offset + limit).min(bytes.len());
+
+    while self.position.offset < max_offset {
+      let &byte = unsafe { bytes.get_unchecked(self.position.offset) };
+
+      let previous_position = self.position;
+
+      if is_continuation_byte(byte) {
+        self.position.offset += 1;
+        continue;
+      }
+
+      let character_width = get_character_width(byte);
+
+      self.position.offset += character_width;
+      self.position.character += 1;
+
+      match byte {
+        b'\r' => {
+          self.position.line += 1;
+          self.position.character = 0;
+          self.four_byte_characters = 0;
+        }
+        b'\n' => {
+          if !is_crlf_line_feed(bytes, previous_position.offset) {
+            self.position.line += 1;
+          }
+          self.position.character = 0;
+          self.four_byte_characters = 0;
+        }
+        _ if self.brackets.is_delimiter(byte) => {
+          // Delimiters are ASCII, so the rest of the sequence is one column per byte.
+          let skipped = self.handle(previous_position).saturating_sub(1);
+          self.position.offset += skipped;
+          self.position.character += skipped;
+        }
+        _ if character_width == 4 => self.four_byte_characters += 1,
+        _ => {}
+      }
+    }
+  }
+
+  /// Ranges matched so far with both columns, indexed like `ranges()`.
+  #[must_use]
+  pub fn dual_ranges(&self) -> Vec<DualRange> {
+    self.brackets.ranges().iter().map(|&range| self.dual_range(range)).collect()
+  }
+
+  /// Adds UTF-16 columns to a range produced by the parser (a matched range or the range of a diagnostic).
+  #[must_use]
+  pub fn dual_range(&self, range: Range) -> DualRange {
+    DualRange { start: self.dual_position(range.start), end: self.dual_position(range.end) }
+  }
+
+  fn dual_position(&self, position: Position) -> DualPosition {
+    // Bounds of ranges are either the starts of delimiter sequences or their ends, and sequences are ASCII, so the
+    // last sequence that starts at or before the position has the same number of 4-byte characters before it.
+    let i = self.sequences.partition_point(|&(offset, _)| offset <= position.offset);
+    let four_byte_characters = i.checked_sub(1).map_or(0, |i| self.sequences[i].1);
+    DualPosition {
+      line: position.line,
+      character: position.character,
+      utf16_character: position.character + four_byte_characters,
+      offset: position.offset,
+    }
+  }
+
+  fn handle(&mut self, position: Position) -> usize {
+    let length = self.brackets.handle(self.input.as_bytes(), position);
+    if length > 0 {
+      self.sequences.push((position.offset, self.four_byte_characters));
+    }
+    length
+  }
+}
+
+/// Numbers of characters and 4-byte characters of a 32-byte block between the bounds.
+const fn count_between(starts: u32, four_byte_starts: u32, start: usize, end: usize) -> (usize, usize) {
+  let bits = bits_between(start, end);
+  ((starts & bits).count_ones() as usize, (four_byte_starts & bits).count_ones() as usize)
+}
+
+#[cfg(test)]
+pub mod tests {
+  extern crate test;
+  use test::bench::Bencher;
+
+  use proptest::prelude::*;
+
+  use super::*;
+  use crate::parser::Strategy;
+  use crate::tests::test_data::*;
+  use crate::utf16_parser::*;
+  use crate::utf32_parser::*;
+
+  fn assert_dual(input: &str, options: &ParseOptions) {
+    let mut utf32 = Utf32Parser::with_options(input, options.clone());
+    let mut utf16 = Utf16Parser::with_options(input, options.clone());
+    let (utf32_ranges, utf16_ranges) = (utf32.parse_chars().to_vec(), utf16.parse_chars().to_vec());
+
+    for strategy in Strategy::ALL {
+      let mut parser = DualParser::with_options(input, options.clone());
+      parser.parse(strategy);
+      let ranges = parser.dual_ranges();
+
+      assert_eq!(ranges.iter().map(|range| range.utf32()).collect::<Vec<_>>(), utf32_ranges, "{}", strategy.name());
+      assert_eq!(ranges.iter().map(|range| range.utf16()).collect::<Vec<_>>(), utf16_ranges, "{}", strategy.name());
+
+      let diagnostics = parser.brackets().diagnostics();
+      assert_eq!(diagnostics, utf32.brackets().diagnostics(), "{}", strategy.name());
+      for (diagnostic, expected) in diagnostics.iter().zip(utf16.brackets().diagnostics()) {
+        assert_eq!(parser.dual_range(diagnostic.range).utf16(), expected.range, "{}", strategy.name());
+      }
+    }
+  }
+
+  proptest! {
+    #[test]
+    fn parse_dual_property_test(s in "[0-9a-zA-Zа-яА-Я😀🎲𝔸\\[\\]\\n\\r]{300}") {
+      assert_dual(&s, &ParseOptions::default());
+    }
+
+    #[test]
+    fn parse_dual_options_property_test(
+      s in "[0-9a-zA-Zа-я😀🎲 \\[\\]()$`~\\\\\\n\\r]{300}",
+      max_depth in 1..8usize,
+      escapes in any::<bool>(),
+      code in any::<bool>(),
+      single_line in any::<bool>(),
+    ) {
+      let delimiters = vec![Delimiter::new(b'[', b']'), Delimiter::new(b'(', b')'), Delimiter::new(b'$', b'$')];
+      assert_dual(&s, &ParseOptions { delimiters, max_depth, escapes, code, single_line });
+    }
+
+    #[test]
+    fn parse_dual_sequences_property_test(s in "[0-9a-zA-Zа-я😀🎲\\[\\]()$`\\\\\\n\\r]{300}", max_depth in 1..8usize) {
+      assert_dual(&s, &ParseOptions { delimiters: sequence_delimiters(), max_depth, ..ParseOptions::default() });
+    }
+  }
+
+  #[test]
+  pub fn parse_dual_test() {
+    let mut parser = DualParser::new("😀[a😀]\n🎲 [б]");
+    parser.parse(Strategy::Bytes);
+    let characters: Vec<_> = parser
+      .dual_ranges()
+      .iter()
+      .map(|range| [range.start.character, range.start.utf16_character, range.end.character, range.end.utf16_character])
+      .collect();
+
+    assert_eq!(characters, [[1, 2, 5, 7], [2, 3, 5, 6]]);
+  }
+
+  #[test]
+  pub fn parse_dual_test_data_test() {
+    for input in
+      [GIBBERISH, SHORT_MULTILINE_INPUT, LONG_MULTILINE_INPUT, DENSE_BRACKETS_INPUT, MIXED_LINE_ENDINGS_INPUT]
+    {
+      assert_dual(input, &ParseOptions::default());
+    }
+  }
+
+  #[bench]
+  pub fn parse_chars_bench(b: &mut Bencher) {
+    b.iter(|| DualParser::new(BENCHMARK_INPUT).parse_chars().len());
+  }
+
+  #[bench]
+  pub fn parse_bytes_bench(b: &mut Bencher) {
+    b.iter(|| DualParser::new(BENCHMARK_INPUT).parse_bytes().len());
+  }
+
+  #[bench]
+  pub fn parse_v128_bench(b: &mut Bencher) {
+    b.iter(|| DualParser::new(BENCHMARK_INPUT).parse_v128().len());
+  }
+
+  #[bench]
+  pub fn parse_v256_bench(b: &mut Bencher) {
+    b.iter(|| DualParser::new(BENCHMARK_INPUT).parse_v256().len());
+  }
+
+  #[bench]
+  pub fn parse_v128_portable_bench(b: &mut Bencher) {
+    b.iter(|| DualParser::new(BENCHMARK_INPUT).parse_v128_portable().len());
+  }
+
+  #[bench]
+  pub fn parse_bitmask_bench(b: &mut Bencher) {
+    b.iter(|| DualParser::new(BENCHMARK_INPUT).parse_bitmask().len());
+  }
+
+  #[bench]
+  pub fn parse_structural_bench(b: &mut Bencher) {
+    b.iter(|| DualParser::new(BENCHMARK_INPUT).parse_structural().len());
+  }
+}
@@ -0,0 +1,280 @@
+//! Emphasis (`*a*`, `_a_`) and strong emphasis (`**a**`, `__a__`), found with the CommonMark delimiter run algorithm.
+
+use crate::code::*;
+use crate::deferred::*;
+use crate::lookup::*;
+use crate::parser::*;
+use crate::types::*;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EmphasisKind {
+  /// `*a*` or `_a_`.
+  Emphasis,
+  /// `**a**` or `__a__`.
+  Strong,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Emphasis {
+  pub kind: EmphasisKind,
+  /// The whole span, including delimiters (1 character long for emphasis and 2 for strong emphasis).
+  pub range: Range,
+}
+
+/// Run of `*` or `_` that can open or close emphasis.
+#[derive(Clone, Copy, Debug)]
+struct DelimiterRun {
+  byte: u8,
+  /// Offset of the first delimiter that isn't matched yet.
+  start: usize,
+  /// Number of delimiters that aren't matched yet.
+  count: usize,
+  /// Length of the run before any delimiters were matched, for the rule of 3.
+  length: usize,
+  can_open: bool,
+  can_close: bool,
+}
+
+/// Finds emphasis with the strategy. Delimiters are searched for with the strategy, so text between them is skipped at
+/// SIMD speed with vector strategies. Code spans and backslash escapes are taken into account; blank lines end
+/// paragraphs (emphasis never spans them), but other block structure and links are not.
+#[must_use]
+pub fn parse_emphasis(input: &str, encoding: Encoding, strategy: Strategy) -> Vec<Emphasis> {
+  let bytes = input.as_bytes();
+  let mut spans = vec![];
+  let mut runs = vec![];
+  let mut line_start = 0;
+  // Offset up to which bytes are already handled (as part of a delimiter run, code span or escape).
+  let mut skip_until = 0;
+
+  for offset in Lookup::new(*b"*_`\\\n\r").find_all(input, strategy) {
+    let byte = bytes[offset];
+    if byte == b'\n' || byte == b'\r' {
+      if byte == b'\r' && bytes.get(offset + 1) == Some(&b'\n') {
+        continue;
+      }
+      if bytes[line_start..offset].iter().all(u8::is_ascii_whitespace) {
+        process_emphasis(&mut runs, &mut spans);
+        runs.clear();
+      }
+      line_start = offset + 1;
+      continue;
+    }
+    if offset < skip_until {
+      continue;
+    }
+
+    match byte {
+      b'\\' if matches!(bytes.get(offset + 1), Some(byte) if byte.is_ascii_punctuation()) => skip_until = offset + 2,
+      b'`' => {
+        let length = run_length(bytes, offset, b'`');
+        skip_until = code_span_end(bytes, offset + length, length).unwrap_or(offset + length);
+      }
+      b'*' | b'_' => {
+        let length = run_length(bytes, offset, byte);
+        skip_until = offset + length;
+        runs.push(delimiter_run(input, offset, length));
+      }
+      _ => {}
+    }
+  }
+  process_emphasis(&mut runs, &mut spans);
+
+  // Bounds are resolved in a single pass over the sorted offsets rather than from the start of each line.
+  let resolve = Resolved::new(input, spans.iter().map(|&(_, range)| range), encoding, strategy);
+  let mut emphasis: Vec<_> =
+    spans.into_iter().map(|(kind, range)| Emphasis { kind, range: resolve.range(range) }).collect();
+  emphasis.sort_by_key(|emphasis| (emphasis.range.start.offset, std::cmp::Reverse(emphasis.range.end.offset)));
+  emphasis
+}
+
+/// Classifies a run of delimiters by the characters around it.
+fn delimiter_run(input: &str, start: usize, length: usize) -> DelimiterRun {
+  let byte = input.as_bytes()[start];
+  // The start and the end of the input count as whitespace.
+  let before = input[..start].chars().next_back().unwrap_or(' ');
+  let after = input[start + length..].chars().next().unwrap_or(' ');
+
+  let (whitespace_before, whitespace_after) = (before.is_whitespace(), after.is_whitespace());
+  let (punctuation_before, punctuation_after) = (is_punctuation(before), is_punctuation(after));
+
+  let left_flanking = !whitespace_after && (!punctuation_after || whitespace_before || punctuation_before);
+  let right_flanking = !whitespace_before && (!punctuation_before || whitespace_after || punctuation_after);
+
+  // `_` can't open or close emphasis inside of words.
+  let (can_open, can_close) = if byte == b'*' {
+    (left_flanking, right_flanking)
+  } else {
+    (left_flanking && (!right_flanking || punctuation_before), right_flanking && (!left_flanking || punctuation_after))
+  };
+
+  DelimiterRun { byte, start, count: length, length, can_open, can_close }
+}
+
+/// Unicode punctuation and symbols (approximated as everything that's neither alphanumeric nor whitespace).
+fn is_punctuation(char: char) -> bool {
+  char.is_ascii_punctuation() || !(char.is_ascii() || char.is_alphanumeric() || char.is_whitespace())
+}
+
+/// Emphasis whose range only has its offsets set.
+type Span = (EmphasisKind, Range);
+
+/// Matches closers with openers of a paragraph, as in "process emphasis" of the CommonMark spec, and adds spans with
+/// offsets of their bounds.
+fn process_emphasis(runs: &mut [DelimiterRun], spans: &mut Vec<Span>) {
+  // Index of the first run that can be an opener, for each delimiter, closer length modulo 3 and closer `can_open`.
+  let mut openers_bottom = [[[0; 2]; 3]; 2];
+
+  let mut closer = 0;
+  while closer < runs.len() {
+    let closer_run = runs[closer];
+    if !closer_run.can_close || closer_run.count == 0 {
+      closer += 1;
+      continue;
+    }
+
+    let bottom = &mut openers_bottom[usize::from(closer_run.byte == b'*')][closer_run.length % 3]
+      [usize::from(closer_run.can_open)];
+    let opener = (*bottom..closer).rev().find(|&i| {
+      let opener_run = runs[i];
+      // The rule of 3: if one of the runs can both open and close, their lengths can't add up to a multiple of 3
+      // (unless both are multiples of 3).
+      let rule_of_3 = (opener_run.can_close || closer_run.can_open)
+        && matches!(
+          ((opener_run.length + closer_run.length) % 3, opener_run.length % 3, closer_run.length % 3),
+          (0, 1 | 2, _) | (0, _, 1 | 2)
+        );
+      opener_run.byte == closer_run.byte && opener_run.can_open && opener_run.count > 0 && !rule_of_3
+    });
+
+    let Some(opener) = opener else {
+      *bottom = closer;
+      if !closer_run.can_open {
+        runs[closer].count = 0;
+      }
+      closer += 1;
+      continue;
+    };
+
+    let (kind, count) = if runs[opener].count >= 2 && closer_run.count >= 2 {
+      (EmphasisKind::Strong, 2)
+    } else {
+      (EmphasisKind::Emphasis, 1)
+    };
+    runs[opener].count -= count;
+    let start = runs[opener].start + runs[opener].count;
+    let end = closer_run.start + count;
+    let bound = |offset| Position { offset, ..Position::default() };
+    spans.push((kind, Range { start: bound(start), end: bound(end) }));
+
+    runs[closer].start = end;
+    runs[closer].count -= count;
+    for run in &mut runs[opener + 1..closer] {
+      run.count = 0;
+    }
+  }
+}
+
+#[cfg(test)]
+pub mod tests {
+  extern crate test;
+  use test::bench::Bencher;
+
+  use proptest::prelude::*;
+
+  use super::*;
+  use crate::parser::Strategy;
+  use crate::tests::test_data::*;
+
+  fn spans(input: &str, strategy: Strategy) -> Vec<(EmphasisKind, &str)> {
+    parse_emphasis(input, Encoding::Utf16, strategy)
+      .iter()
+      .map(|emphasis| (emphasis.kind, &input[emphasis.range.start.offset..emphasis.range.end.offset]))
+      .collect()
+  }
+
+  fn assert_emphasis(input: &str, expected: &[(EmphasisKind, &str)]) {
+    for strategy in Strategy::ALL {
+      assert_eq!(spans(input, strategy), expected, "{input:?} {}", strategy.name());
+    }
+  }
+
+  proptest! {
+    #[test]
+    fn parse_emphasis_property_test(s in "[aя !*_`\\\\\\n\\r]{300}") {
+      let expected = parse_emphasis(&s, Encoding::Utf16, Strategy::Chars);
+      let position = |offset| Encoding::Utf16.advance(&s, Position::default(), offset);
+      for &Emphasis { range, .. } in &expected {
+        assert_eq!(range, Range { start: position(range.start.offset), end: position(range.end.offset) });
+      }
+      for strategy in Strategy::ALL {
+        assert_eq!(parse_emphasis(&s, Encoding::Utf16, strategy), expected);
+      }
+    }
+  }
+
+  #[test]
+  pub fn parse_emphasis_test() {
+    use EmphasisKind::*;
+
+    assert_emphasis(
+      "*a* _b_ **c** __d__",
+      &[(Emphasis, "*a*"), (Emphasis, "_b_"), (Strong, "**c**"), (Strong, "__d__")],
+    );
+    assert_emphasis("***a***", &[(Emphasis, "***a***"), (Strong, "**a**")]);
+    assert_emphasis("*a **b** c*", &[(Emphasis, "*a **b** c*"), (Strong, "**b**")]);
+
+    // Flanking.
+    assert_emphasis("a * b * c", &[]);
+    assert_emphasis("a*\"b\"*c", &[]);
+    assert_emphasis("*(a)*", &[(Emphasis, "*(a)*")]);
+
+    // Intraword.
+    assert_emphasis("in*tra*word", &[(Emphasis, "*tra*")]);
+    assert_emphasis("in_tra_word snake_case_name", &[]);
+    assert_emphasis("ы_ф_ы _ф_", &[(Emphasis, "_ф_")]);
+
+    // Rule of 3.
+    assert_emphasis("*a**b*", &[(Emphasis, "*a**b*")]);
+    assert_emphasis("*a**b**c*", &[(Emphasis, "*a**b**c*"), (Strong, "**b**")]);
+    assert_emphasis("**a*", &[(Emphasis, "*a*")]);
+
+    // Escapes, code spans and paragraphs.
+    assert_emphasis("\\*a* *`b*` c*", &[(Emphasis, "*`b*` c*")]);
+    assert_emphasis("*a\nb*\r\n \r\n*c\n\nd*", &[(Emphasis, "*a\nb*")]);
+  }
+
+  #[test]
+  pub fn parse_emphasis_test_data_test() {
+    let strong = spans(LONG_MULTILINE_INPUT, Strategy::Vector128);
+    assert_eq!(strong.iter().filter(|&&span| span == (EmphasisKind::Strong, "**Определение**")).count(), 11);
+    assert!(strong.contains(&(EmphasisKind::Emphasis, "_Парсинг_")));
+    assert!(strong.contains(&(EmphasisKind::Emphasis, "_Parsing Techniques: A Practical Guide_")));
+    assert_eq!(strong.len(), 23);
+  }
+
+  #[test]
+  pub fn parse_emphasis_characters_test() {
+    let input = "😀 **Ы\n😀 _a_**";
+    let bounds = |encoding| {
+      let emphasis = parse_emphasis(input, encoding, Strategy::Vector128Portable);
+      let Range { start, end } = emphasis[0].range;
+      let inner = emphasis[1].range.start;
+      [start.line, start.character, end.line, end.character, inner.character]
+    };
+
+    assert_eq!(bounds(Encoding::Utf8), [0, 5, 1, 10, 5]);
+    assert_eq!(bounds(Encoding::Utf16), [0, 3, 1, 8, 3]);
+    assert_eq!(bounds(Encoding::Utf32), [0, 2, 1, 7, 2]);
+  }
+
+  #[bench]
+  pub fn parse_emphasis_bytes_bench(b: &mut Bencher) {
+    b.iter(|| parse_emphasis(BENCHMARK_INPUT, Encoding::Utf16, Strategy::Bytes).len());
+  }
+
+  #[bench]
+  pub fn parse_emphasis_v256_bench(b: &mut Bencher) {
+    b.iter(|| parse_emphasis(BENCHMARK_INPUT, Encoding::Utf16, Strategy::Vector256).len());
+  }
+}
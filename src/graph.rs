@@ -0,0 +1,371 @@
+//! Graph of links between notes, with backlinks, orphans and clusters, and its export to Graphviz DOT and JSON.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use rayon::prelude::*;
+
+use crate::inline::*;
+use crate::links::*;
+use crate::parser::*;
+use crate::types::*;
+use crate::utf16_parser::*;
+use crate::wiki_link::*;
+
+/// Link from one note to another.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Edge {
+  pub source: usize,
+  pub target: usize,
+  /// Range of the link in the source note, in UTF-16 positions.
+  pub range: Range,
+}
+
+/// Notes and the links between them. Notes are referred to by their index in [`LinkGraph::notes`]. Only links to
+/// other notes (inline links and wiki-links) become edges; links to other files and URLs are left out.
+#[derive(Clone, Debug)]
+pub struct LinkGraph {
+  root: PathBuf,
+  notes: Vec<PathBuf>,
+  edges: Vec<Edge>,
+  outgoing: Vec<Vec<usize>>,
+  incoming: Vec<Vec<usize>>,
+}
+
+impl LinkGraph {
+  /// Parses every `.md` file under `root` in parallel.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the directory can't be traversed or a note can't be read.
+  pub fn from_directory(root: &Path, strategy: Strategy) -> io::Result<Self> {
+    let notes = markdown_files(root)?
+      .into_par_iter()
+      .map(|path| Ok((path.clone(), std::fs::read_to_string(path)?)))
+      .collect::<io::Result<Vec<_>>>()?;
+    Ok(Self::from_notes(root, &notes, strategy))
+  }
+
+  /// Builds the graph from notes that are already read. Paths are resolved relative to `root` as in
+  /// [`check_directory`].
+  #[must_use]
+  pub fn from_notes(root: &Path, notes: &[(PathBuf, String)], strategy: Strategy) -> Self {
+    let paths: Vec<_> = notes.iter().map(|(path, _)| normalize(path)).collect();
+    let indices: HashMap<_, _> = paths.iter().enumerate().map(|(i, path)| (path.as_path(), i)).collect();
+    let mut stems = HashMap::new();
+    for (i, path) in paths.iter().enumerate() {
+      if let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) {
+        stems.entry(stem).or_insert(i);
+      }
+    }
+
+    let edges: Vec<_> = notes
+      .par_iter()
+      .enumerate()
+      .flat_map_iter(|(source, (_, input))| {
+        let path = &paths[source];
+        let links = parse_inlines::<Utf16Parser>(input, strategy).into_iter().filter_map(|inline| {
+          let range = inline.destination?;
+          let (target, _) = resolve(root, path, &input[range.start.offset..range.end.offset])?;
+          indices.get(target.as_path()).map(|&target| Edge { source, target, range: inline.range })
+        });
+
+        let wiki_links = parse_wiki_links::<Utf16Parser>(input, strategy).into_iter().filter_map(|link| {
+          let target = &input[link.target.start.offset..link.target.end.offset];
+          let target = match target {
+            "" => Some(source),
+            _ if target.contains('/') => {
+              let path = root.join(target);
+              let path = if path.extension().is_some() { path } else { path.with_extension("md") };
+              indices.get(normalize(&path).as_path()).copied()
+            }
+            _ => stems.get(target.strip_suffix(".md").unwrap_or(target)).copied(),
+          };
+          target.map(|target| Edge { source, target, range: link.range })
+        });
+
+        let mut edges: Vec<_> = links.chain(wiki_links).collect();
+        edges.sort_by_key(|edge| edge.range.start.offset);
+        edges
+      })
+      .collect();
+
+    let mut outgoing = vec![vec![]; paths.len()];
+    let mut incoming = vec![vec![]; paths.len()];
+    for edge in &edges {
+      if !outgoing[edge.source].contains(&edge.target) {
+        outgoing[edge.source].push(edge.target);
+        incoming[edge.target].push(edge.source);
+      }
+    }
+    for targets in outgoing.iter_mut().chain(&mut incoming) {
+      targets.sort_unstable();
+    }
+
+    Self { root: root.to_owned(), notes: paths, edges, outgoing, incoming }
+  }
+
+  #[must_use]
+  pub fn notes(&self) -> &[PathBuf] {
+    &self.notes
+  }
+
+  /// Every link between notes, including repeated links between the same pair.
+  #[must_use]
+  pub fn edges(&self) -> &[Edge] {
+    &self.edges
+  }
+
+  /// Notes that the note links to.
+  #[must_use]
+  pub fn outgoing(&self, note: usize) -> &[usize] {
+    &self.outgoing[note]
+  }
+
+  /// Notes that link to the note.
+  #[must_use]
+  pub fn backlinks(&self, note: usize) -> &[usize] {
+    &self.incoming[note]
+  }
+
+  /// Notes that neither link to other notes nor are linked from them.
+  #[must_use]
+  pub fn orphans(&self) -> Vec<usize> {
+    (0..self.notes.len())
+      .filter(|&note| self.backlinks(note).iter().chain(self.outgoing(note)).all(|&other| other == note))
+      .collect()
+  }
+
+  /// Strongly connected components with more than one note, i.e. groups of notes that are all reachable from each
+  /// other. Found with Tarjan's algorithm (without recursion, so long chains of notes can't overflow the stack).
+  #[must_use]
+  pub fn clusters(&self) -> Vec<Vec<usize>> {
+    const UNVISITED: usize = usize::MAX;
+
+    let count = self.notes.len();
+    let mut index = vec![UNVISITED; count];
+    let mut low_link = vec![0; count];
+    let mut on_stack = vec![false; count];
+    let mut stack = vec![];
+    let mut next_index = 0;
+    let mut clusters = vec![];
+
+    for root in 0..count {
+      if index[root] != UNVISITED {
+        continue;
+      }
+
+      // (note, number of outgoing links visited so far)
+      let mut calls = vec![(root, 0)];
+      while let Some(&mut (note, ref mut visited)) = calls.last_mut() {
+        if *visited == 0 {
+          index[note] = next_index;
+          low_link[note] = next_index;
+          next_index += 1;
+          stack.push(note);
+          on_stack[note] = true;
+        }
+
+        if let Some(&target) = self.outgoing[note].get(*visited) {
+          *visited += 1;
+          if index[target] == UNVISITED {
+            calls.push((target, 0));
+          } else if on_stack[target] {
+            low_link[note] = low_link[note].min(index[target]);
+          }
+          continue;
+        }
+
+        calls.pop();
+        if let Some(&(parent, _)) = calls.last() {
+          low_link[parent] = low_link[parent].min(low_link[note]);
+        }
+        if low_link[note] == index[note] {
+          let mut cluster = vec![];
+          while let Some(member) = stack.pop() {
+            on_stack[member] = false;
+            cluster.push(member);
+            if member == note {
+              break;
+            }
+          }
+          if cluster.len() > 1 {
+            cluster.sort_unstable();
+            clusters.push(cluster);
+          }
+        }
+      }
+    }
+
+    clusters.sort_unstable();
+    clusters
+  }
+
+  /// Exports the graph to Graphviz DOT, with one edge per pair of linked notes.
+  #[must_use]
+  pub fn to_dot(&self) -> String {
+    let mut dot = String::from("digraph notes {\n");
+    for (note, path) in self.notes.iter().enumerate() {
+      let _ = writeln!(dot, "  {note} [label=\"{}\"];", escape(&self.relative_path(path)));
+    }
+    for (source, targets) in self.outgoing.iter().enumerate() {
+      for target in targets {
+        let _ = writeln!(dot, "  {source} -> {target};");
+      }
+    }
+    dot.push_str("}\n");
+    dot
+  }
+
+  /// Exports the graph to JSON: notes with their outgoing links and backlinks, every link with its position, orphans
+  /// and clusters.
+  #[must_use]
+  pub fn to_json(&self) -> String {
+    let list = |items: &[usize]| items.iter().map(usize::to_string).collect::<Vec<_>>().join(",");
+
+    let notes: Vec<_> = self
+      .notes
+      .iter()
+      .enumerate()
+      .map(|(note, path)| {
+        format!(
+          "{{\"id\":{note},\"path\":\"{}\",\"outgoing\":[{}],\"backlinks\":[{}]}}",
+          escape(&self.relative_path(path)),
+          list(self.outgoing(note)),
+          list(self.backlinks(note)),
+        )
+      })
+      .collect();
+    let edges: Vec<_> = self
+      .edges
+      .iter()
+      .map(|edge| {
+        let Range { start, end } = edge.range;
+        format!(
+          "{{\"source\":{},\"target\":{},\"range\":[[{},{}],[{},{}]]}}",
+          edge.source, edge.target, start.line, start.character, end.line, end.character
+        )
+      })
+      .collect();
+    let clusters: Vec<_> = self.clusters().iter().map(|cluster| format!("[{}]", list(cluster))).collect();
+
+    format!(
+      "{{\"notes\":[{}],\"edges\":[{}],\"orphans\":[{}],\"clusters\":[{}]}}",
+      notes.join(","),
+      edges.join(","),
+      list(&self.orphans()),
+      clusters.join(",")
+    )
+  }
+
+  fn relative_path(&self, path: &Path) -> String {
+    path.strip_prefix(&self.root).unwrap_or(path).to_string_lossy().into_owned()
+  }
+}
+
+/// Escapes a string for both DOT and JSON string literals.
+fn escape(text: &str) -> String {
+  let mut escaped = String::with_capacity(text.len());
+  for char in text.chars() {
+    match char {
+      '"' => escaped.push_str("\\\""),
+      '\\' => escaped.push_str("\\\\"),
+      '\n' => escaped.push_str("\\n"),
+      _ if char.is_control() => {
+        let _ = write!(escaped, "\\u{:04x}", char as u32);
+      }
+      _ => escaped.push(char),
+    }
+  }
+  escaped
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn graph() -> LinkGraph {
+    let notes = [
+      ("/notes/a.md", "[b](b.md) [b again](./b.md#x) [web](https://example.com)"),
+      ("/notes/b.md", "[[a]] [[sub/c|C]] [[#self]]"),
+      ("/notes/sub/c.md", "[a](../a.md)"),
+      ("/notes/sub/d \"quoted\".md", "[missing](missing.md)"),
+      ("/notes/e.md", "[[e]]"),
+    ];
+    let notes: Vec<_> = notes.iter().map(|&(path, input)| (PathBuf::from(path), input.to_owned())).collect();
+    LinkGraph::from_notes(Path::new("/notes"), &notes, Strategy::Vector128)
+  }
+
+  #[test]
+  pub fn link_graph_test() {
+    let graph = graph();
+
+    assert_eq!(graph.edges().len(), 7);
+    assert_eq!(graph.outgoing(0), [1]);
+    assert_eq!(graph.outgoing(1), [0, 1, 2]);
+    assert_eq!(graph.backlinks(0), [1, 2]);
+    assert_eq!(graph.backlinks(2), [1]);
+    assert_eq!(graph.orphans(), [3, 4]);
+    assert_eq!(graph.clusters(), [vec![0, 1, 2]]);
+
+    let edge = graph.edges()[3];
+    assert_eq!((edge.source, edge.target), (1, 2));
+    assert_eq!((edge.range.start.character, edge.range.end.character), (6, 17));
+  }
+
+  #[test]
+  pub fn clusters_test() {
+    let notes: Vec<_> = [("a", "[[b]]"), ("b", "[[a]] [[c]]"), ("c", "[[d]]"), ("d", "[[c]]"), ("e", "[[a]]")]
+      .iter()
+      .map(|&(name, input)| (PathBuf::from(format!("/{name}.md")), input.to_owned()))
+      .collect();
+    let graph = LinkGraph::from_notes(Path::new("/"), &notes, Strategy::Chars);
+
+    assert_eq!(graph.clusters(), [vec![0, 1], vec![2, 3]]);
+  }
+
+  #[test]
+  pub fn to_dot_test() {
+    assert_eq!(
+      graph().to_dot(),
+      "digraph notes {
+  0 [label=\"a.md\"];
+  1 [label=\"b.md\"];
+  2 [label=\"sub/c.md\"];
+  3 [label=\"sub/d \\\"quoted\\\".md\"];
+  4 [label=\"e.md\"];
+  0 -> 1;
+  1 -> 0;
+  1 -> 1;
+  1 -> 2;
+  2 -> 0;
+  4 -> 4;
+}
+"
+    );
+  }
+
+  #[test]
+  pub fn to_json_test() {
+    assert_eq!(
+      graph().to_json(),
+      concat!(
+        r#"{"notes":[{"id":0,"path":"a.md","outgoing":[1],"backlinks":[1,2]},"#,
+        r#"{"id":1,"path":"b.md","outgoing":[0,1,2],"backlinks":[0,1]},"#,
+        r#"{"id":2,"path":"sub/c.md","outgoing":[0],"backlinks":[1]},"#,
+        r#"{"id":3,"path":"sub/d \"quoted\".md","outgoing":[],"backlinks":[]},"#,
+        r#"{"id":4,"path":"e.md","outgoing":[4],"backlinks":[4]}],"#,
+        r#""edges":[{"source":0,"target":1,"range":[[0,0],[0,9]]},"#,
+        r#"{"source":0,"target":1,"range":[[0,10],[0,29]]},"#,
+        r#"{"source":1,"target":0,"range":[[0,0],[0,5]]},"#,
+        r#"{"source":1,"target":2,"range":[[0,6],[0,17]]},"#,
+        r#"{"source":1,"target":1,"range":[[0,18],[0,27]]},"#,
+        r#"{"source":2,"target":0,"range":[[0,0],[0,12]]},"#,
+        r#"{"source":4,"target":4,"range":[[0,0],[0,5]]}],"#,
+        r#""orphans":[3,4],"clusters":[[0,1,2]]}"#
+      )
+    );
+  }
+}
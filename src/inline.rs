@@ -0,0 +1,339 @@
+//! Markdown inline constructs (links, images, citations, etc.) built from bracket ranges.
+
+use crate::parser::*;
+use crate::types::*;
+use crate::utf8::*;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InlineKind {
+  /// `[text](destination "title")`
+  Link,
+  /// `![alt](source "title")` or `![alt][reference]`
+  Image,
+  /// `[text][reference]`, including collapsed `[text][]`.
+  ReferenceLink,
+  /// `[@key, 14]` (see pandoc's `citations` extension).
+  Citation,
+  /// `[^id]`
+  Footnote,
+  /// `[ ]` or `[x]` right after a list item marker.
+  TaskCheckbox,
+  /// Any other pair of brackets, e.g. a shortcut reference link or plain text.
+  Bracket,
+}
+
+/// Classified bracket range.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Inline {
+  pub kind: InlineKind,
+  /// The whole construct, e.g. `![alt](source)`.
+  pub range: Range,
+  /// Text inside the (first pair of) brackets, without `^` for footnotes.
+  pub label: Range,
+  /// Text inside the second pair of brackets of reference links and images.
+  pub reference: Option<Range>,
+  /// Destination of links and images, without angle brackets.
+  pub destination: Option<Range>,
+  /// Title of links and images, without quotes or parentheses.
+  pub title: Option<Range>,
+}
+
+/// Options of the bracket scan that [`parse_inlines`] is built on: square brackets that may be nested (images inside
+/// links, brackets inside link text), escapes and code spans.
+#[must_use]
+pub fn inline_options() -> ParseOptions {
+  ParseOptions { max_depth: 8, escapes: true, code: true, ..ParseOptions::default() }
+}
+
+/// Finds brackets with the strategy and classifies them.
+#[must_use]
+pub fn parse_inlines<'a, P: RangeParser<'a>>(input: &'a str, strategy: Strategy) -> Vec<Inline> {
+  let mut parser = P::with_options(input, inline_options());
+  classify(input, parser.parse(strategy), P::ENCODING)
+}
+
+/// Classifies bracket ranges that were found in the input by a parser with the encoding. Only the bytes right around
+/// the brackets are looked at, so this doesn't undo the savings of a vectorised scan. Inlines are ordered by their
+/// start.
+#[must_use]
+pub fn classify(input: &str, ranges: &[Range], encoding: Encoding) -> Vec<Inline> {
+  let bytes = input.as_bytes();
+  let mut ranges = ranges.to_vec();
+  ranges.sort_unstable_by_key(|range| range.start.offset);
+
+  let mut is_reference = vec![false; ranges.len()];
+  let mut inlines = Vec::with_capacity(ranges.len());
+
+  for (i, &range) in ranges.iter().enumerate() {
+    if is_reference[i] {
+      continue;
+    }
+
+    let start = range.start.offset;
+    let end = range.end.offset;
+    let label = inner(range);
+    let mut inline =
+      Inline { kind: InlineKind::Bracket, range, label, reference: None, destination: None, title: None };
+
+    if let Some(tail) = parse_link_tail(bytes, end) {
+      let mut cursor = range.end;
+      let mut advance = |offset| {
+        cursor = encoding.advance(input, cursor, offset);
+        cursor
+      };
+
+      inline.kind = InlineKind::Link;
+      inline.destination = Some(Range { start: advance(tail.destination.0), end: advance(tail.destination.1) });
+      inline.title = tail.title.map(|(start, end)| Range { start: advance(start), end: advance(end) });
+      inline.range.end = advance(tail.end);
+    } else if let Ok(reference) = ranges.binary_search_by_key(&end, |range| range.start.offset) {
+      is_reference[reference] = true;
+      inline.kind = InlineKind::ReferenceLink;
+      inline.reference = Some(inner(ranges[reference]));
+      inline.range.end = ranges[reference].end;
+    }
+
+    if inline.kind != InlineKind::Bracket {
+      if start > 0 && bytes[start - 1] == b'!' && !is_escaped(bytes, start - 1) {
+        inline.kind = InlineKind::Image;
+        inline.range.start = backward(range.start, 1);
+      }
+    } else if bytes[start + 1] == b'^' && end - start > 3 {
+      inline.kind = InlineKind::Footnote;
+      inline.label.start = forward(label.start, 1);
+    } else if is_citation(&bytes[start + 1..end - 1]) {
+      inline.kind = InlineKind::Citation;
+    } else if is_task_checkbox(bytes, start, end) {
+      inline.kind = InlineKind::TaskCheckbox;
+    }
+
+    inlines.push(inline);
+  }
+
+  inlines
+}
+
+/// Destination, title and end offset of an inline link.
+struct LinkTail {
+  destination: (usize, usize),
+  title: Option<(usize, usize)>,
+  end: usize,
+}
+
+/// Parses `(destination "title")` at `offset`.
+fn parse_link_tail(bytes: &[u8], offset: usize) -> Option<LinkTail> {
+  if bytes.get(offset) != Some(&b'(') {
+    return None;
+  }
+
+  let mut i = skip_whitespace(bytes, offset + 1);
+  let destination = if bytes.get(i) == Some(&b'<') {
+    let start = i + 1;
+    let length = bytes[start..].iter().position(|&byte| matches!(byte, b'<' | b'>' | b'\n' | b'\r'))?;
+    if bytes[start + length] != b'>' {
+      return None;
+    }
+    i = start + length + 1;
+    (start, start + length)
+  } else {
+    let start = i;
+    let mut depth = 0;
+    while let Some(&byte) = bytes.get(i) {
+      match byte {
+        b'\\' if matches!(bytes.get(i + 1), Some(next) if next.is_ascii_punctuation()) => i += 1,
+        b'(' => depth += 1,
+        b')' if depth == 0 => break,
+        b')' => depth -= 1,
+        _ if byte <= b' ' => break,
+        _ => {}
+      }
+      i += 1;
+    }
+    if depth != 0 {
+      return None;
+    }
+    (start, i)
+  };
+
+  let mut title = None;
+  let whitespace_end = skip_whitespace(bytes, i);
+  if whitespace_end > i {
+    i = whitespace_end;
+    if let Some(&quote @ (b'"' | b'\'' | b'(')) = bytes.get(i) {
+      let close = if quote == b'(' { b')' } else { quote };
+      let start = i + 1;
+      let mut end = start;
+      while end < bytes.len() && bytes[end] != close {
+        end += if bytes[end] == b'\\' { 2 } else { 1 };
+      }
+      if end >= bytes.len() {
+        return None;
+      }
+      title = Some((start, end));
+      i = skip_whitespace(bytes, end + 1);
+    }
+  }
+
+  (bytes.get(i) == Some(&b')')).then_some(LinkTail { destination, title, end: i + 1 })
+}
+
+/// Checks whether the text inside brackets contains a pandoc citation, i.e. `@` (or `-@`) followed by a key at the
+/// beginning of the text or after a space or `;`.
+fn is_citation(text: &[u8]) -> bool {
+  text.iter().enumerate().any(|(i, &byte)| {
+    let is_key_start =
+      matches!(text.get(i + 1), Some(&next) if next.is_ascii_alphanumeric() || matches!(next, b'_' | b'{') || next >= 0x80);
+    let prefix = if i > 0 && text[i - 1] == b'-' { i - 1 } else { i };
+    byte == b'@' && is_key_start && (prefix == 0 || matches!(text[prefix - 1], b' ' | b'\t' | b';' | b'\n' | b'\r'))
+  })
+}
+
+/// Checks whether `[ ]`, `[x]` or `[X]` directly follows a list item marker (`-`, `*`, `+`, `1.` or `1)`).
+fn is_task_checkbox(bytes: &[u8], start: usize, end: usize) -> bool {
+  if end - start != 3 || !matches!(bytes[start + 1], b' ' | b'x' | b'X') {
+    return false;
+  }
+  if matches!(bytes.get(end), Some(byte) if !byte.is_ascii_whitespace()) {
+    return false;
+  }
+
+  let line_start = bytes[..start].iter().rposition(|&byte| byte == b'\n' || byte == b'\r').map_or(0, |i| i + 1);
+  let prefix = &bytes[line_start..start];
+  let marker = prefix.iter().position(|&byte| byte != b' ' && byte != b'\t').unwrap_or(prefix.len());
+  let spacing = prefix.iter().rev().take_while(|&&byte| byte == b' ').count();
+  if spacing == 0 || marker + spacing >= prefix.len() {
+    return false;
+  }
+
+  match &prefix[marker..prefix.len() - spacing] {
+    [b'-' | b'*' | b'+'] => true,
+    [digits @ .., b'.' | b')'] => !digits.is_empty() && digits.len() <= 9 && digits.iter().all(u8::is_ascii_digit),
+    _ => false,
+  }
+}
+
+fn skip_whitespace(bytes: &[u8], offset: usize) -> usize {
+  offset + bytes[offset.min(bytes.len())..].iter().take_while(|byte| byte.is_ascii_whitespace()).count()
+}
+
+/// Range between a pair of brackets. Brackets are ASCII, so they are a single column wide in every encoding.
+const fn inner(range: Range) -> Range {
+  Range { start: forward(range.start, 1), end: backward(range.end, 1) }
+}
+
+const fn forward(position: Position, columns: usize) -> Position {
+  Position { line: position.line, character: position.character + columns, offset: position.offset + columns }
+}
+
+const fn backward(position: Position, columns: usize) -> Position {
+  Position { line: position.line, character: position.character - columns, offset: position.offset - columns }
+}
+
+#[cfg(test)]
+pub mod tests {
+  extern crate test;
+  use test::bench::Bencher;
+
+  use super::*;
+  use crate::tests::test_data::*;
+  use crate::utf16_parser::*;
+  use crate::utf32_parser::*;
+  use crate::utf8_parser::*;
+
+  type Offsets = (usize, usize);
+  type Expected = (InlineKind, Offsets, Offsets, Option<Offsets>, Option<Offsets>, Option<Offsets>);
+
+  const fn offsets(range: Range) -> Offsets {
+    (range.start.offset, range.end.offset)
+  }
+
+  fn assert_inlines<'a, P: RangeParser<'a>>(input: &'a str, expected: &[Expected]) {
+    for strategy in Strategy::ALL {
+      let actual: Vec<_> = parse_inlines::<P>(input, strategy)
+        .into_iter()
+        .map(|inline| {
+          (
+            inline.kind,
+            offsets(inline.range),
+            offsets(inline.label),
+            inline.reference.map(offsets),
+            inline.destination.map(offsets),
+            inline.title.map(offsets),
+          )
+        })
+        .collect();
+      assert_eq!(actual, expected, "{}", strategy.name());
+    }
+  }
+
+  #[test]
+  pub fn classify_test() {
+    use InlineKind::*;
+
+    let input =
+      "- [ ] task [^1] ![img](a.png \"T\") [text][ref] [@key, 14] [plain] [link](<b c>)\n1. [x] done [ф](ф.md)";
+    let expected = [
+      (TaskCheckbox, (2, 5), (3, 4), None, None, None),
+      (Footnote, (11, 15), (13, 14), None, None, None),
+      (Image, (16, 33), (18, 21), None, Some((23, 28)), Some((30, 31))),
+      (ReferenceLink, (34, 45), (35, 39), Some((41, 44)), None, None),
+      (Citation, (46, 56), (47, 55), None, None, None),
+      (Bracket, (57, 64), (58, 63), None, None, None),
+      (Link, (65, 78), (66, 70), None, Some((73, 76)), None),
+      (TaskCheckbox, (82, 85), (83, 84), None, None, None),
+      (Link, (91, 102), (92, 94), None, Some((96, 101)), None),
+    ];
+
+    assert_inlines::<Utf32Parser>(input, &expected);
+    assert_inlines::<Utf16Parser>(input, &expected);
+    assert_inlines::<Utf8Parser>(input, &expected);
+  }
+
+  fn assert_link_characters<'a, P: RangeParser<'a>>(input: &'a str, expected: (Offsets, Offsets, usize)) {
+    let characters = |range: Range| (range.start.character, range.end.character);
+
+    for strategy in Strategy::ALL {
+      let inlines = parse_inlines::<P>(input, strategy);
+      let actual = (
+        characters(inlines[0].destination.unwrap()),
+        characters(inlines[0].title.unwrap()),
+        inlines[0].range.end.character,
+      );
+      assert_eq!(actual, expected, "{}", strategy.name());
+    }
+  }
+
+  #[test]
+  pub fn link_characters_test() {
+    let input = "[😀](😀.md \"😀\")";
+
+    assert_link_characters::<Utf32Parser>(input, ((4, 8), (10, 11), 13));
+    assert_link_characters::<Utf16Parser>(input, ((5, 10), (12, 14), 16));
+    assert_link_characters::<Utf8Parser>(input, ((7, 14), (16, 20), 22));
+  }
+
+  #[test]
+  pub fn test_data_test() {
+    let inlines = parse_inlines::<Utf16Parser>(SHORT_MULTILINE_INPUT, Strategy::Vector128);
+    assert_eq!(inlines.len(), 8);
+    for inline in inlines {
+      assert_eq!(inline.kind, InlineKind::Link);
+      let destination = inline.destination.unwrap();
+      assert!(SHORT_MULTILINE_INPUT[destination.start.offset..destination.end.offset].ends_with(".md"));
+    }
+
+    let inlines = parse_inlines::<Utf32Parser>(LONG_MULTILINE_INPUT, Strategy::Vector256);
+    assert!(inlines.iter().all(|inline| inline.kind == InlineKind::Citation));
+    assert_eq!(inlines.len(), LONG_MULTILINE_INPUT.matches("[@").count());
+  }
+
+  #[bench]
+  pub fn parse_inlines_chars_bench(b: &mut Bencher) {
+    b.iter(|| parse_inlines::<Utf16Parser>(BENCHMARK_INPUT, Strategy::Chars).len());
+  }
+
+  #[bench]
+  pub fn parse_inlines_v128_portable_bench(b: &mut Bencher) {
+    b.iter(|| parse_inlines::<Utf16Parser>(BENCHMARK_INPUT, Strategy::Vector128Portable).len());
+  }
+}
@@ -45,10 +45,28 @@
 )]
 #![deny(clippy::semicolon_if_nothing_returned)]
 
+pub mod bibliography;
+pub mod block;
+pub mod brackets;
+pub mod citation;
+pub mod code;
+pub mod deferred;
+pub mod dual_parser;
+pub mod emphasis;
+pub mod graph;
+pub mod inline;
+pub mod links;
+pub mod lookup;
+pub mod math;
+pub mod outline;
+pub mod parser;
+pub mod structural;
 pub mod types;
 pub mod utf16_parser;
 pub mod utf32_parser;
 pub mod utf8;
+pub mod utf8_parser;
+pub mod wiki_link;
 
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,286 @@
+//! Validation of relative link destinations across a directory of Markdown notes.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use rayon::prelude::*;
+
+use crate::inline::*;
+use crate::outline::*;
+use crate::parser::*;
+use crate::types::*;
+use crate::utf16_parser::*;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum BrokenLinkKind {
+  /// Neither a note nor any other file exists at the path.
+  MissingFile,
+  /// The note exists but has no heading with the anchor.
+  MissingAnchor(String),
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct BrokenLink {
+  pub kind: BrokenLinkKind,
+  /// Note that contains the link.
+  pub source: PathBuf,
+  /// Range of the link destination in UTF-16 positions.
+  pub range: Range,
+  /// Resolved path of the link target.
+  pub path: PathBuf,
+}
+
+/// Links and heading anchors of a note.
+struct Note {
+  path: PathBuf,
+  input: String,
+  links: Vec<Inline>,
+  anchors: HashSet<String>,
+}
+
+/// Parses every `.md` file under `root` in parallel and reports inline links (and images) whose relative destinations
+/// don't exist, or whose `#anchor` doesn't match any heading of the target note. Links with a URL scheme are skipped.
+///
+/// # Errors
+///
+/// Returns an error if the directory can't be traversed or a note can't be read.
+pub fn check_directory(root: &Path, strategy: Strategy) -> io::Result<Vec<BrokenLink>> {
+  let notes = markdown_files(root)?
+    .into_par_iter()
+    .map(|path| {
+      let input = std::fs::read_to_string(&path)?;
+      let links = parse_inlines::<Utf16Parser>(&input, strategy)
+        .into_iter()
+        .filter(|inline| matches!(inline.kind, InlineKind::Link | InlineKind::Image))
+        .collect();
+      let anchors = heading_anchors(&input, strategy);
+      Ok(Note { path: normalize(&path), input, links, anchors })
+    })
+    .collect::<io::Result<Vec<_>>>()?;
+
+  let anchors: HashMap<_, _> = notes.iter().map(|note| (note.path.as_path(), &note.anchors)).collect();
+
+  Ok(
+    notes
+      .par_iter()
+      .flat_map_iter(|note| {
+        let anchors = &anchors;
+        note.links.iter().filter_map(move |link| {
+          let range = link.destination?;
+          let destination = &note.input[range.start.offset..range.end.offset];
+          let (path, anchor) = resolve(root, &note.path, destination)?;
+
+          let kind = match anchors.get(path.as_path()) {
+            Some(target_anchors) => match anchor {
+              // The fragment is normalised like heading text, so `#Intro` finds the `intro` anchor.
+              Some(anchor) if !target_anchors.contains(&slug(&anchor)) => BrokenLinkKind::MissingAnchor(anchor),
+              _ => return None,
+            },
+            None if path.exists() => return None,
+            None => BrokenLinkKind::MissingFile,
+          };
+          Some(BrokenLink { kind, source: note.path.clone(), range, path })
+        })
+      })
+      .collect(),
+  )
+}
+
+/// Paths of `.md` files under the directory (recursively), sorted.
+///
+/// # Errors
+///
+/// Returns an error if the directory can't be traversed.
+pub fn markdown_files(root: &Path) -> io::Result<Vec<PathBuf>> {
+  let mut paths = vec![];
+  collect_markdown_files(root, &mut paths)?;
+  paths.sort();
+  Ok(paths)
+}
+
+fn collect_markdown_files(directory: &Path, paths: &mut Vec<PathBuf>) -> io::Result<()> {
+  for entry in std::fs::read_dir(directory)? {
+    let entry = entry?;
+    let path = entry.path();
+    // Symlinked directories aren't followed, as they may form a cycle (symlinked notes are still collected).
+    if entry.file_type()?.is_dir() {
+      collect_markdown_files(&path, paths)?;
+    } else if path.extension() == Some("md".as_ref()) && path.is_file() {
+      paths.push(path);
+    }
+  }
+  Ok(())
+}
+
+/// Resolves a link destination relative to the note that contains it. Returns `None` for URLs (destinations with a
+/// scheme such as `https:` or `mailto:`). Destinations that start with `/` are relative to `root`.
+#[must_use]
+pub fn resolve(root: &Path, source: &Path, destination: &str) -> Option<(PathBuf, Option<String>)> {
+  let (path, anchor) = match destination.split_once('#') {
+    Some((path, anchor)) => (path, Some(percent_decode(anchor))),
+    None => (destination, None),
+  };
+
+  let scheme = path.split_once(':').map_or("", |(scheme, _)| scheme);
+  let is_url = !scheme.is_empty() && scheme.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"+-.".contains(&byte));
+  if is_url || path.starts_with("//") {
+    return None;
+  }
+
+  let path = percent_decode(path);
+  let path = if path.is_empty() {
+    source.to_owned()
+  } else if let Some(path) = path.strip_prefix('/') {
+    root.join(path)
+  } else {
+    source.parent().unwrap_or(root).join(path)
+  };
+
+  Some((normalize(&path), anchor))
+}
+
+/// Removes `.` and resolves `..` without touching the file system.
+#[must_use]
+pub fn normalize(path: &Path) -> PathBuf {
+  let mut normalized = PathBuf::new();
+  for component in path.components() {
+    match component {
+      Component::CurDir => {}
+      Component::ParentDir if normalized.file_name().is_some() => {
+        normalized.pop();
+      }
+      _ => normalized.push(component),
+    }
+  }
+  normalized
+}
+
+fn percent_decode(text: &str) -> String {
+  let bytes = text.as_bytes();
+  let mut decoded = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    let hex = bytes.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+    match (bytes[i], hex) {
+      (b'%', Some(byte)) => {
+        decoded.push(byte);
+        i += 3;
+      }
+      (byte, _) => {
+        decoded.push(byte);
+        i += 1;
+      }
+    }
+  }
+  String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Anchors of headings, as generated by GitHub: lowercase text without punctuation, with spaces replaced by `-` and
+/// numeric suffixes for duplicates.
+fn heading_anchors(input: &str, strategy: Strategy) -> HashSet<String> {
+  let mut anchors = HashSet::new();
+
+  for heading in parse_outline(input, Encoding::Utf8, strategy) {
+    let slug = slug(&input[heading.text.start.offset..heading.text.end.offset]);
+    let mut anchor = slug.clone();
+    let mut suffix = 0;
+    while !anchors.insert(anchor.clone()) {
+      suffix += 1;
+      anchor = format!("{slug}-{suffix}");
+    }
+  }
+
+  anchors
+}
+
+fn slug(text: &str) -> String {
+  text
+    .chars()
+    .filter_map(|char| match char {
+      ' ' => Some('-'),
+      '-' | '_' => Some(char),
+      _ if char.is_alphanumeric() => Some(char),
+      _ => None,
+    })
+    .flat_map(char::to_lowercase)
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tests::test_data::*;
+
+  #[test]
+  pub fn resolve_test() {
+    let root = Path::new("/notes");
+    let source = Path::new("/notes/a/index.md");
+
+    assert_eq!(resolve(root, source, "b.md"), Some(("/notes/a/b.md".into(), None)));
+    assert_eq!(
+      resolve(root, source, "../c%20d.md#Some%20Heading"),
+      Some(("/notes/c d.md".into(), Some("Some Heading".into())))
+    );
+    assert_eq!(resolve(root, source, "/e/./f.md"), Some(("/notes/e/f.md".into(), None)));
+    assert_eq!(resolve(root, source, "#heading"), Some(("/notes/a/index.md".into(), Some("heading".into()))));
+    assert_eq!(resolve(root, source, "https://example.com/a.md"), None);
+    assert_eq!(resolve(root, source, "mailto:someone@example.com"), None);
+  }
+
+  #[test]
+  pub fn heading_anchors_test() {
+    let anchors = heading_anchors(
+      "# Starfinder\n## Кампании ##\r\n#NotHeading\n    # Code\n## Бой: правила\n## Бой: правила\n\nSetext\n---",
+      Strategy::Vector128,
+    );
+    let mut anchors: Vec<_> = anchors.into_iter().collect();
+    anchors.sort();
+    assert_eq!(anchors, ["setext", "starfinder", "бой-правила", "бой-правила-1", "кампании"]);
+  }
+
+  #[test]
+  pub fn check_directory_test() {
+    let root = std::env::temp_dir().join(format!("parsing-sandbox-links-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(root.join("campaigns")).unwrap();
+
+    std::fs::write(root.join("index.md"), SHORT_MULTILINE_INPUT).unwrap();
+    std::fs::write(root.join("20220820_mousetrap-campaign-starfinder.md"), "# Мышеловка\n\n[Назад](index.md#кампании)")
+      .unwrap();
+    std::fs::write(
+      root.join("campaigns/notes.md"),
+      "[Бой](../20220918_combat-starfinder.md) [x](../index.md#нет) [y](../index.md#Кампании)",
+    )
+    .unwrap();
+    std::fs::write(root.join("20220918_combat-starfinder.md"), "![map](map.png) [web](https://example.com)").unwrap();
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(&root, root.join("campaigns/root")).unwrap();
+
+    let broken = check_directory(&root, Strategy::Vector128).unwrap();
+    let actual: Vec<_> = broken
+      .iter()
+      .map(|link| {
+        let source = link.source.strip_prefix(&root).unwrap().to_str().unwrap();
+        let path = link.path.strip_prefix(&root).unwrap().to_str().unwrap();
+        (link.kind.clone(), source, path, link.range.start.line, link.range.start.character)
+      })
+      .collect();
+    std::fs::remove_dir_all(&root).unwrap();
+
+    let missing = |path, line, character| (BrokenLinkKind::MissingFile, "index.md", path, line, character);
+    assert_eq!(
+      actual,
+      [
+        (BrokenLinkKind::MissingFile, "20220918_combat-starfinder.md", "map.png", 0, 7),
+        (BrokenLinkKind::MissingAnchor("нет".into()), "campaigns/notes.md", "index.md", 0, 44),
+        missing("20220813_knopka-character-starfinder.md", 9, 11),
+        missing("20220817_knopka-stealth-drone.md", 10, 32),
+        missing("20220918_conditions-starfinder.md", 14, 14),
+        missing("20220827_space-fight-starfinder.md", 16, 20),
+        missing("20220827_engineer-role-starfinder.md", 18, 20),
+        missing("20220827_science-officer-role-starfinder.md", 19, 24),
+      ]
+    );
+  }
+}
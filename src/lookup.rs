@@ -0,0 +1,177 @@
+//! SIMD lookups for a set of bytes that is only known at runtime.
+
+use std::arch::x86_64::*;
+use std::simd::{mask8x16, u8x16, Simd, SimdPartialEq, SimdUint};
+
+use crate::parser::*;
+use crate::structural::*;
+
+/// Set of bytes the parsers have to stop at. Blocks without any of them are skipped in vector mode.
+#[derive(Clone, Debug)]
+pub struct Lookup {
+  bytes: Vec<u8>,
+}
+
+impl Lookup {
+  #[must_use]
+  pub fn new(bytes: impl IntoIterator<Item = u8>) -> Self {
+    let mut bytes: Vec<_> = bytes.into_iter().collect();
+    bytes.sort_unstable();
+    bytes.dedup();
+    Self { bytes }
+  }
+
+  #[must_use]
+  pub fn bytes(&self) -> &[u8] {
+    &self.bytes
+  }
+
+  /// Whether any byte of the 16-byte block is in the set.
+  #[inline]
+  #[must_use]
+  pub fn hit_v128(&self, v: __m128i) -> bool {
+    let mut lookup = unsafe { _mm_setzero_si128() };
+    for &byte in &self.bytes {
+      lookup = unsafe { _mm_or_si128(lookup, _mm_cmpeq_epi8(v, _mm_set1_epi8(byte as i8))) };
+    }
+
+    let lookup: Simd<u8, 16> = lookup.into();
+    lookup.reduce_or() != 0
+  }
+
+  /// Whether any byte of the 32-byte block is in the set.
+  #[inline]
+  #[must_use]
+  pub fn hit_v256(&self, v: __m256i) -> bool {
+    let mut lookup = unsafe { _mm256_setzero_si256() };
+    for &byte in &self.bytes {
+      lookup = unsafe { _mm256_or_si256(lookup, _mm256_cmpeq_epi8(v, _mm256_set1_epi8(byte as i8))) };
+    }
+
+    let lookup: Simd<u8, 32> = lookup.into();
+    lookup.reduce_or() != 0
+  }
+
+  /// Bitmask of the bytes of the 32-byte block that are in the set (bit `i` is set for byte `i`).
+  #[inline]
+  #[must_use]
+  pub fn mask_v256(&self, v: __m256i) -> u32 {
+    let mut lookup = unsafe { _mm256_setzero_si256() };
+    for &byte in &self.bytes {
+      lookup = unsafe { _mm256_or_si256(lookup, _mm256_cmpeq_epi8(v, _mm256_set1_epi8(byte as i8))) };
+    }
+
+    unsafe { _mm256_movemask_epi8(lookup) as u32 }
+  }
+
+  /// Whether any byte of the 16-byte block is in the set.
+  #[inline]
+  #[must_use]
+  pub fn hit_v128_portable(&self, v: u8x16) -> bool {
+    let mut lookup = mask8x16::splat(false);
+    for &byte in &self.bytes {
+      lookup |= v.simd_eq(u8x16::splat(byte));
+    }
+
+    lookup.any()
+  }
+
+  /// Offsets of all bytes of the input that are in the set, found with the strategy. Vector strategies only look at
+  /// individual bytes of blocks that contain any of them.
+  #[must_use]
+  pub fn find_all(&self, input: &str, strategy: Strategy) -> Vec<usize> {
+    let bytes = input.as_bytes();
+    let mut offsets = vec![];
+    let mut offset = 0;
+
+    match strategy {
+      Strategy::Chars => {
+        offsets.extend(
+          input
+            .char_indices()
+            .filter(|&(_, char)| char.is_ascii() && self.bytes.contains(&(char as u8)))
+            .map(|(i, _)| i),
+        );
+        offset = bytes.len();
+      }
+      Strategy::Bytes => {}
+      Strategy::Vector128 => {
+        while offset + 15 < bytes.len() {
+          if self.hit_v128(unsafe { _mm_loadu_si128(bytes[offset..].as_ptr().cast()) }) {
+            self.find_all_scalar(bytes, offset, offset + 16, &mut offsets);
+          }
+          offset += 16;
+        }
+      }
+      Strategy::Vector256 => {
+        while offset + 31 < bytes.len() {
+          if self.hit_v256(unsafe { _mm256_loadu_si256(bytes[offset..].as_ptr().cast()) }) {
+            self.find_all_scalar(bytes, offset, offset + 32, &mut offsets);
+          }
+          offset += 32;
+        }
+      }
+      Strategy::Vector128Portable => {
+        while offset + 15 < bytes.len() {
+          if self.hit_v128_portable(u8x16::from_slice(&bytes[offset..])) {
+            self.find_all_scalar(bytes, offset, offset + 16, &mut offsets);
+          }
+          offset += 16;
+        }
+      }
+      Strategy::Bitmask256 => {
+        while offset + 31 < bytes.len() {
+          let mut hits = self.mask_v256(unsafe { _mm256_loadu_si256(bytes[offset..].as_ptr().cast()) });
+          while hits != 0 {
+            offsets.push(offset + hits.trailing_zeros() as usize);
+            hits &= hits - 1;
+          }
+          offset += 32;
+        }
+      }
+      Strategy::Structural => {
+        return StructuralIndex::new(input, self).offsets().iter().map(|&offset| offset as usize).collect();
+      }
+    }
+    self.find_all_scalar(bytes, offset, bytes.len(), &mut offsets);
+
+    offsets
+  }
+
+  fn find_all_scalar(&self, bytes: &[u8], start: usize, end: usize, offsets: &mut Vec<usize>) {
+    offsets.extend((start..end).filter(|&i| self.bytes.contains(&bytes[i])));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  pub fn lookup_test() {
+    let lookup = Lookup::new(*b"()\n(");
+    assert_eq!(lookup.bytes(), b"\n()");
+
+    let block = *b"0123456789abcdef0123456789abcdef";
+    let mut with_hit = block;
+    with_hit[21] = b')';
+
+    for (bytes, expected) in [(block, false), (with_hit, true)] {
+      let v128 = unsafe { _mm_loadu_si128(bytes[16..].as_ptr().cast()) };
+      let v256 = unsafe { _mm256_loadu_si256(bytes.as_ptr().cast()) };
+
+      assert_eq!(lookup.hit_v128(v128), expected);
+      assert_eq!(lookup.hit_v256(v256), expected);
+      assert_eq!(lookup.mask_v256(v256), u32::from(expected) << 21);
+      assert_eq!(lookup.hit_v128_portable(u8x16::from_slice(&bytes[16..])), expected);
+    }
+  }
+
+  #[test]
+  pub fn find_all_test() {
+    let input = "(ы)________________________________(\n)";
+    for strategy in Strategy::ALL {
+      assert_eq!(Lookup::new(*b"()\n").find_all(input, strategy), [0, 3, 36, 37, 38], "{}", strategy.name());
+    }
+  }
+}
@@ -2,17 +2,10 @@ use std::process::exit;
 
 use rayon::prelude::*;
 
+use parsing_sandbox::parser::*;
 use parsing_sandbox::utf16_parser::*;
 use parsing_sandbox::utf32_parser::*;
-
-#[derive(Copy, Clone)]
-pub enum ParserName {
-  Chars,
-  Bytes,
-  Vector128,
-  Vector256,
-  Vector128Portable,
-}
+use parsing_sandbox::utf8_parser::*;
 
 #[derive(Copy, Clone)]
 pub enum ModeName {
@@ -24,44 +17,19 @@ pub enum ModeName {
 pub enum OutputName {
   Utf32,
   Utf16,
+  Utf8,
 }
 
-fn parse_utf32(input: &str, parser_name: ParserName) -> usize {
-  let mut parser = Utf32Parser::new(input);
-  match parser_name {
-    ParserName::Chars => parser.parse_chars(),
-    ParserName::Bytes => parser.parse_bytes(),
-    ParserName::Vector128 => parser.parse_v128(),
-    ParserName::Vector256 => parser.parse_v256(),
-    ParserName::Vector128Portable => parser.parse_v128_portable(),
-  }
-  .len()
-}
-
-fn parse_utf16(input: &str, parser_name: ParserName) -> usize {
-  let mut parser = Utf16Parser::new(input);
-  match parser_name {
-    ParserName::Chars => parser.parse_chars(),
-    ParserName::Bytes => parser.parse_bytes(),
-    ParserName::Vector128Portable => parser.parse_v128_portable(),
-    _ => todo!(),
-  }
-  .len()
+fn parse<'a, P: RangeParser<'a>>(input: &'a str, strategy: Strategy) -> usize {
+  P::new(input).parse(strategy).len()
 }
 
 pub fn main() {
   let args: Vec<_> = std::env::args().collect();
 
-  let parser_name = match args.get(1).map(|s| s.as_str()) {
-    Some("chars") => ParserName::Chars,
-    Some("bytes") => ParserName::Bytes,
-    Some("vector128") => ParserName::Vector128,
-    Some("vector256") => ParserName::Vector256,
-    Some("vector128portable") => ParserName::Vector128Portable,
-    _ => {
-      eprintln!("Expected first argument to be parser name (one of: 'chars', 'bytes', 'vector128', 'vector256', 'vector128portable').");
-      exit(1);
-    }
+  let Some(strategy) = args.get(1).and_then(|s| Strategy::from_name(s)) else {
+    eprintln!("Expected first argument to be parser name (one of: 'chars', 'bytes', 'vector128', 'vector256', 'vector128portable', 'bitmask256', 'structural').");
+    exit(1);
   };
 
   let mode_name = match args.get(2).map(|s| s.as_str()) {
@@ -76,8 +44,9 @@ pub fn main() {
   let output_name = match args.get(3).map(|s| s.as_str()) {
     Some("utf32") => OutputName::Utf32,
     Some("utf16") => OutputName::Utf16,
+    Some("utf8") => OutputName::Utf8,
     _ => {
-      eprintln!("Expected third argument to be output name (one of: 'utf32', 'utf16').");
+      eprintln!("Expected third argument to be output name (one of: 'utf32', 'utf16', 'utf8').");
       exit(1);
     }
   };
@@ -90,8 +59,9 @@ pub fn main() {
         let input = simdutf8::basic::from_utf8(&input).unwrap();
 
         match output_name {
-          OutputName::Utf32 => sum += parse_utf32(input, parser_name),
-          OutputName::Utf16 => sum += parse_utf16(input, parser_name),
+          OutputName::Utf32 => sum += parse::<Utf32Parser>(input, strategy),
+          OutputName::Utf16 => sum += parse::<Utf16Parser>(input, strategy),
+          OutputName::Utf8 => sum += parse::<Utf8Parser>(input, strategy),
         }
       }
       sum
@@ -103,8 +73,9 @@ pub fn main() {
         let input = simdutf8::compat::from_utf8(&input).unwrap();
 
         match output_name {
-          OutputName::Utf32 => parse_utf32(input, parser_name),
-          OutputName::Utf16 => parse_utf16(input, parser_name),
+          OutputName::Utf32 => parse::<Utf32Parser>(input, strategy),
+          OutputName::Utf16 => parse::<Utf16Parser>(input, strategy),
+          OutputName::Utf8 => parse::<Utf8Parser>(input, strategy),
         }
       })
       .sum(),
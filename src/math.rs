@@ -0,0 +1,231 @@
+//! TeX math spans (`$…$` and `$$…$$`), as in pandoc's `tex_math_dollars` extension.
+
+use crate::code::*;
+use crate::deferred::*;
+use crate::lookup::*;
+use crate::parser::*;
+use crate::types::*;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Math {
+  /// Whether the math is display math (`$$…$$`) rather than inline math (`$…$`).
+  pub display: bool,
+  /// The whole span, including dollar signs.
+  pub range: Range,
+  /// TeX between dollar signs.
+  pub tex: Range,
+}
+
+/// Finds math spans with the strategy. The opening `$` of inline math must be followed by a non-space character, and
+/// the closing one must be preceded by a non-space character and not followed by a digit. `\$` is a literal dollar
+/// sign (inside math it's passed through as TeX), and dollar signs in code spans are skipped. Math never spans blank
+/// lines.
+#[must_use]
+pub fn parse_math(input: &str, encoding: Encoding, strategy: Strategy) -> Vec<Math> {
+  let bytes = input.as_bytes();
+  let hits = Lookup::new(*b"$\\`\n\r").find_all(input, strategy);
+  let line_starts: Vec<_> = std::iter::once(0)
+    .chain(hits.iter().filter(|&&offset| is_line_end(bytes, offset)).map(|&offset| offset + 1))
+    .collect();
+
+  let mut spans = vec![];
+  // Hit index, offset and `display` of the opening dollar sign.
+  let mut open: Option<(usize, usize, bool)> = None;
+  // Offset up to which bytes are already handled (as part of a delimiter, code span or escape).
+  let mut skip_until = 0;
+  let mut i = 0;
+  // Scanning inside of math only depends on the hit that is being looked at, so once a scan gets to a hit that a scan
+  // of an unclosed span (of the same kind) has been through, it's bound to fail as well. Hits are marked by `display`,
+  // and each of them is scanned as a part of an unclosed span at most once per kind.
+  let mut failed = [vec![false; hits.len()], vec![false; hits.len()]];
+  // Hits scanned since the opening dollar sign.
+  let mut scanned = vec![];
+
+  loop {
+    let offset = hits.get(i).copied();
+    if matches!(offset, Some(offset) if offset < skip_until) {
+      i += 1;
+      continue;
+    }
+
+    if let Some((hit, start, display)) = open {
+      let blank_line =
+        matches!(offset, Some(offset) if is_line_end(bytes, offset) && is_blank_line(bytes, &line_starts, offset));
+      if offset.is_none() || blank_line || failed[usize::from(display)][i] {
+        // An unclosed opening dollar sign is literal, and the input is scanned again right after it.
+        for &scanned_hit in &scanned {
+          failed[usize::from(display)][scanned_hit] = true;
+        }
+        scanned.clear();
+        open = None;
+        (i, skip_until) = (hit + 1, start + 1);
+        continue;
+      }
+      scanned.push(i);
+    }
+
+    let Some(offset) = offset else {
+      break;
+    };
+    i += 1;
+
+    match (bytes[offset], open) {
+      (b'\\', None) if matches!(bytes.get(offset + 1), Some(byte) if byte.is_ascii_punctuation()) => {
+        skip_until = offset + 2;
+      }
+      (b'\\', Some(_)) => skip_until = offset + 2,
+      (b'`', None) => {
+        let length = run_length(bytes, offset, b'`');
+        skip_until = code_span_end(bytes, offset + length, length).unwrap_or(offset + length);
+      }
+      (b'$', None) => match bytes.get(offset + 1) {
+        Some(b'$') => {
+          open = Some((i - 1, offset, true));
+          skip_until = offset + 2;
+        }
+        Some(_) if !input[offset + 1..].starts_with(char::is_whitespace) => open = Some((i - 1, offset, false)),
+        _ => {}
+      },
+      (b'$', Some((_, start, false))) => {
+        let closes = !input[..offset].ends_with(char::is_whitespace)
+          && !matches!(bytes.get(offset + 1), Some(byte) if byte.is_ascii_digit());
+        if closes {
+          spans.push((false, start, offset + 1));
+          open = None;
+          scanned.clear();
+        }
+      }
+      (b'$', Some((_, start, true))) if bytes.get(offset + 1) == Some(&b'$') => {
+        spans.push((true, start, offset + 2));
+        open = None;
+        scanned.clear();
+        skip_until = offset + 2;
+      }
+      _ => {}
+    }
+  }
+
+  let range = |start, end| Range {
+    start: Position { offset: start, ..Position::default() },
+    end: Position { offset: end, ..Position::default() },
+  };
+  let spans: Vec<_> = spans
+    .into_iter()
+    .map(|(display, start, end)| {
+      let delimiter = if display { 2 } else { 1 };
+      (display, range(start, end), range(start + delimiter, end - delimiter))
+    })
+    .collect();
+  // Bounds are resolved in a single pass over the sorted offsets rather than from the start of each line.
+  let resolve = Resolved::new(input, spans.iter().flat_map(|&(_, range, tex)| [range, tex]), encoding, strategy);
+  spans
+    .into_iter()
+    .map(|(display, range, tex)| Math { display, range: resolve.range(range), tex: resolve.range(tex) })
+    .collect()
+}
+
+/// Whether the byte at `offset` ends a line (`\r` followed by `\n` doesn't).
+fn is_line_end(bytes: &[u8], offset: usize) -> bool {
+  match bytes[offset] {
+    b'\n' => true,
+    b'\r' => bytes.get(offset + 1) != Some(&b'\n'),
+    _ => false,
+  }
+}
+
+/// Whether the line that ends at `offset` is blank.
+fn is_blank_line(bytes: &[u8], line_starts: &[usize], offset: usize) -> bool {
+  let line = line_starts.partition_point(|&start| start <= offset) - 1;
+  bytes[line_starts[line]..offset].iter().all(u8::is_ascii_whitespace)
+}
+
+#[cfg(test)]
+pub mod tests {
+  extern crate test;
+  use test::bench::Bencher;
+
+  use proptest::prelude::*;
+
+  use super::*;
+  use crate::parser::Strategy;
+  use crate::tests::test_data::*;
+
+  fn spans(input: &str, strategy: Strategy) -> Vec<(bool, &str, &str)> {
+    let text = |range: Range| &input[range.start.offset..range.end.offset];
+    parse_math(input, Encoding::Utf16, strategy)
+      .iter()
+      .map(|math| (math.display, text(math.range), text(math.tex)))
+      .collect()
+  }
+
+  fn assert_math(input: &str, expected: &[(bool, &str, &str)]) {
+    for strategy in Strategy::ALL {
+      assert_eq!(spans(input, strategy), expected, "{input:?} {}", strategy.name());
+    }
+  }
+
+  proptest! {
+    #[test]
+    fn parse_math_property_test(s in "[aя 1$`\\\\\\n\\r]{300}") {
+      let expected = parse_math(&s, Encoding::Utf16, Strategy::Chars);
+      let position = |offset| Encoding::Utf16.advance(&s, Position::default(), offset);
+      for &Math { range, tex, .. } in &expected {
+        assert_eq!(range, Range { start: position(range.start.offset), end: position(range.end.offset) });
+        assert_eq!(tex, Range { start: position(tex.start.offset), end: position(tex.end.offset) });
+      }
+      for strategy in Strategy::ALL {
+        assert_eq!(parse_math(&s, Encoding::Utf16, strategy), expected);
+      }
+    }
+  }
+
+  #[test]
+  pub fn parse_math_test() {
+    assert_math("$a$ $$b c$$", &[(false, "$a$", "a"), (true, "$$b c$$", "b c")]);
+    assert_math("$ a$ $b $", &[]);
+    assert_math("$20,000 and $30,000", &[]);
+    assert_math("$a$1 $b$", &[(false, "$a$1 $b$", "a$1 $b")]);
+    assert_math("\\$a$ $b\\$c$", &[(false, "$b\\$c$", "b\\$c")]);
+    assert_math("`$a$` $b$", &[(false, "$b$", "b")]);
+    assert_math("$$\r\nx\r\n$$ $a\n \nb$ $$c", &[(true, "$$\r\nx\r\n$$", "\r\nx\r\n")]);
+    assert_math("$$a $b$", &[(false, "$a $b$", "a $b")]);
+    assert_math("$a $$1 x $$1 $b $c", &[(true, "$$1 x $$", "1 x ")]);
+  }
+
+  #[test]
+  pub fn parse_math_test_data_test() {
+    let math = spans(LONG_MULTILINE_INPUT, Strategy::Vector256);
+    assert_eq!(math.len(), 13);
+    assert!(math.contains(&(false, "$(V_N, V_T, R, S)$", "(V_N, V_T, R, S)")));
+    assert!(math.contains(&(
+      false,
+      "$\\forall (P, Q) \\in R, 1 \\leq P \\leq Q$",
+      "\\forall (P, Q) \\in R, 1 \\leq P \\leq Q"
+    )));
+    assert!(math.iter().all(|&(display, ..)| !display));
+  }
+
+  #[test]
+  pub fn parse_math_characters_test() {
+    let input = "😀 $😀$\n$$😀\n$$";
+    let bounds = |encoding| {
+      let math = parse_math(input, encoding, Strategy::Bytes);
+      let (inline, display) = (math[0], math[1]);
+      [inline.range.start.character, inline.tex.start.character, inline.range.end.character, display.tex.end.line]
+    };
+
+    assert_eq!(bounds(Encoding::Utf8), [5, 6, 11, 2]);
+    assert_eq!(bounds(Encoding::Utf16), [3, 4, 7, 2]);
+    assert_eq!(bounds(Encoding::Utf32), [2, 3, 5, 2]);
+  }
+
+  #[bench]
+  pub fn parse_math_bytes_bench(b: &mut Bencher) {
+    b.iter(|| parse_math(BENCHMARK_INPUT, Encoding::Utf16, Strategy::Bytes).len());
+  }
+
+  #[bench]
+  pub fn parse_math_v256_bench(b: &mut Bencher) {
+    b.iter(|| parse_math(BENCHMARK_INPUT, Encoding::Utf16, Strategy::Vector256).len());
+  }
+}
@@ -0,0 +1,294 @@
+//! Document outline: ATX (`## Heading`) and Setext (`Heading` underlined with `===` or `---`) headings with the
+//! sections they open.
+
+use crate::code::*;
+use crate::lookup::*;
+use crate::parser::*;
+use crate::types::*;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Heading {
+  /// 1 to 6 for ATX headings, 1 (`===`) or 2 (`---`) for Setext headings.
+  pub level: usize,
+  /// The whole heading, including the Setext underline.
+  pub range: Range,
+  /// Heading text without `#`s and surrounding whitespace. Can span multiple lines for Setext headings.
+  pub text: Range,
+  /// From the start of the heading to the start of the next heading of the same or a higher level (or the end of the
+  /// input). Suits LSP document symbols (with `text` as the selection range) and folding ranges.
+  pub section: Range,
+}
+
+/// Line of the input without its line ending.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct Line {
+  pub start: usize,
+  pub end: usize,
+}
+
+/// Finds headings, skipping fenced code blocks and indented code. Lines are found with the strategy; block quotes and
+/// list items are only recognized well enough to not mistake their lines for Setext headings.
+#[must_use]
+pub fn parse_outline(input: &str, encoding: Encoding, strategy: Strategy) -> Vec<Heading> {
+  let bytes = input.as_bytes();
+  let lines = lines(input, strategy);
+  let position = |line: usize, offset| {
+    let start = Position { line, character: 0, offset: lines[line].start };
+    encoding.advance(input, start, offset)
+  };
+
+  let mut headings = vec![];
+  // First line of the current paragraph and the last line that belongs to it.
+  let mut paragraph: Option<(usize, usize)> = None;
+  // Whether the previous lines belong to a block quote or a list item, which lazily continues until a blank line.
+  let mut container = false;
+  let mut fence_end = 0;
+
+  for (i, &Line { start, end }) in lines.iter().enumerate() {
+    if start < fence_end {
+      continue;
+    }
+
+    let indentation = bytes[start..end].iter().take_while(|&&byte| byte == b' ').count();
+    let first = start + indentation;
+    if bytes[first..end].iter().all(|&byte| is_space(byte)) {
+      paragraph = None;
+      container = false;
+      continue;
+    }
+    if indentation > 3 {
+      paragraph = paragraph.map(|(first_line, _)| (first_line, i));
+      continue;
+    }
+
+    if let Some(block_end) = fenced_block_end(bytes, first) {
+      fence_end = block_end;
+      paragraph = None;
+      continue;
+    }
+
+    if let Some((level, text_start, text_end)) = atx_heading(bytes, first, end) {
+      let range = Range { start: position(i, start), end: position(i, end) };
+      let text = Range { start: position(i, text_start), end: position(i, text_end) };
+      headings.push(Heading { level, range, text, section: range });
+      paragraph = None;
+      container = false;
+      continue;
+    }
+
+    if let (Some((first_line, last_line)), Some(level)) = (paragraph, setext_underline(bytes, first, end)) {
+      let text_start = lines[first_line].start;
+      let text_start = text_start + bytes[text_start..].iter().take_while(|&&byte| byte == b' ').count();
+      let text_end = trim_end(bytes, lines[first_line].start, lines[last_line].end);
+      let range = Range { start: position(first_line, lines[first_line].start), end: position(i, end) };
+      let text = Range { start: position(first_line, text_start), end: position(last_line, text_end) };
+      headings.push(Heading { level, range, text, section: range });
+      paragraph = None;
+      continue;
+    }
+
+    if is_thematic_break(bytes, first, end) {
+      paragraph = None;
+    } else if is_container_start(bytes, first, end) {
+      paragraph = None;
+      container = true;
+    } else if !container {
+      paragraph = Some(paragraph.map_or((i, i), |(first_line, _)| (first_line, i)));
+    }
+  }
+
+  let input_end = position(lines.len() - 1, input.len());
+  for i in 0..headings.len() {
+    let level = headings[i].level;
+    let end =
+      headings[i + 1..].iter().find(|heading| heading.level <= level).map_or(input_end, |next| next.range.start);
+    headings[i].section.end = end;
+  }
+
+  headings
+}
+
+/// Finds lines of the input with the strategy.
+pub(crate) fn lines(input: &str, strategy: Strategy) -> Vec<Line> {
+  let bytes = input.as_bytes();
+  let mut lines = vec![];
+  let mut start = 0;
+
+  for offset in Lookup::new(*b"\n\r").find_all(input, strategy) {
+    match bytes[offset] {
+      b'\n' if offset > 0 && bytes[offset - 1] == b'\r' => lines.push(Line { start, end: offset - 1 }),
+      b'\r' if bytes.get(offset + 1) == Some(&b'\n') => continue,
+      _ => lines.push(Line { start, end: offset }),
+    }
+    start = offset + 1;
+  }
+
+  lines.push(Line { start, end: bytes.len() });
+  lines
+}
+
+/// If an ATX heading starts at `first`, returns its level and the offsets of its text.
+pub(crate) fn atx_heading(bytes: &[u8], first: usize, end: usize) -> Option<(usize, usize, usize)> {
+  let level = run_length(&bytes[..end], first, b'#');
+  let content_start = first + level;
+  if !(1..=6).contains(&level) || !(content_start == end || matches!(bytes[content_start], b' ' | b'\t')) {
+    return None;
+  }
+
+  let mut text_end = trim_end(bytes, content_start, end);
+  let closing_start = text_end - bytes[content_start..text_end].iter().rev().take_while(|&&byte| byte == b'#').count();
+  if closing_start == content_start || matches!(bytes[closing_start - 1], b' ' | b'\t') {
+    text_end = trim_end(bytes, content_start, closing_start);
+  }
+  let text_start = content_start + bytes[content_start..text_end].iter().take_while(|&&byte| is_space(byte)).count();
+
+  Some((level, text_start, text_end))
+}
+
+/// Level of the Setext heading that a line of `=`s (1) or `-`s (2) would underline.
+pub(crate) fn setext_underline(bytes: &[u8], first: usize, end: usize) -> Option<usize> {
+  let level = match bytes[first] {
+    b'=' => 1,
+    b'-' => 2,
+    _ => return None,
+  };
+  let underline_end = first + run_length(&bytes[..end], first, bytes[first]);
+  (trim_end(bytes, underline_end, end) == underline_end).then_some(level)
+}
+
+/// `***`, `---` or `___`, optionally separated by spaces and tabs.
+pub(crate) fn is_thematic_break(bytes: &[u8], first: usize, end: usize) -> bool {
+  let marker = bytes[first];
+  matches!(marker, b'*' | b'-' | b'_')
+    && bytes[first..end].iter().all(|&byte| byte == marker || is_space(byte))
+    && bytes[first..end].iter().filter(|&&byte| byte == marker).count() >= 3
+}
+
+/// Block quote (`>`) or list item (`-`, `+`, `*`, `1.`, `1)`).
+fn is_container_start(bytes: &[u8], first: usize, end: usize) -> bool {
+  let marker_end = match bytes[first] {
+    b'>' => return true,
+    b'-' | b'+' | b'*' => first + 1,
+    _ => {
+      let digits = bytes[first..end].iter().take_while(|byte| byte.is_ascii_digit()).count();
+      if !(1..=9).contains(&digits) || !matches!(bytes.get(first + digits), Some(b'.' | b')')) {
+        return false;
+      }
+      first + digits + 1
+    }
+  };
+  marker_end == end || is_space(bytes[marker_end])
+}
+
+fn trim_end(bytes: &[u8], start: usize, end: usize) -> usize {
+  end - bytes[start..end].iter().rev().take_while(|&&byte| is_space(byte)).count()
+}
+
+pub(crate) const fn is_space(byte: u8) -> bool {
+  byte == b' ' || byte == b'\t'
+}
+
+#[cfg(test)]
+pub mod tests {
+  extern crate test;
+  use test::bench::Bencher;
+
+  use proptest::prelude::*;
+
+  use super::*;
+  use crate::parser::Strategy;
+  use crate::tests::test_data::*;
+
+  type Summary<'a> = (usize, &'a str, usize, usize);
+
+  /// Level, text, and lines of the section of each heading.
+  fn assert_outline(input: &str, expected: &[Summary]) {
+    for strategy in Strategy::ALL {
+      let actual: Vec<_> = parse_outline(input, Encoding::Utf16, strategy)
+        .iter()
+        .map(|heading| {
+          let text = &input[heading.text.start.offset..heading.text.end.offset];
+          (heading.level, text, heading.section.start.line, heading.section.end.line)
+        })
+        .collect();
+      assert_eq!(actual, expected, "{}", strategy.name());
+    }
+  }
+
+  proptest! {
+    #[test]
+    fn parse_outline_property_test(s in "[aя #=\\-*>`~\\n\\r]{300}") {
+      let expected = parse_outline(&s, Encoding::Utf16, Strategy::Chars);
+      for strategy in Strategy::ALL {
+        assert_eq!(parse_outline(&s, Encoding::Utf16, strategy), expected);
+      }
+    }
+  }
+
+  #[test]
+  pub fn parse_outline_test() {
+    let input = concat!(
+      "Заголовок\n=====\r\n",
+      "## ATX ##\n",
+      "```\n# code\n```\n",
+      "two\nlines  \n---\n",
+      "- item\n---\n",
+      "### Closing#\n",
+      "#NotHeading\n",
+      "    # indented\n",
+      "\n---\n",
+      "# \n",
+      "text",
+    );
+    let expected =
+      [(1, "Заголовок", 0, 16), (2, "ATX", 2, 6), (2, "two\nlines", 6, 16), (3, "Closing#", 11, 16), (1, "", 16, 17)];
+
+    assert_outline(input, &expected);
+  }
+
+  #[test]
+  pub fn parse_outline_thematic_break_test() {
+    assert_outline("---\nFoo\n---", &[(2, "Foo", 1, 2)]);
+    assert_outline("Intro\n===\n***\nNext\n---", &[(1, "Intro", 0, 4), (2, "Next", 3, 4)]);
+  }
+
+  #[test]
+  pub fn parse_outline_test_data_test() {
+    assert_outline(
+      SHORT_MULTILINE_INPUT,
+      &[(1, "Starfinder", 1, 20), (2, "Кампании", 3, 7), (2, "Персонажи", 7, 12), (2, "Правила", 12, 20)],
+    );
+    assert_outline(
+      LONG_MULTILINE_INPUT,
+      &[
+        (1, "Grune, Dick, and Ceriel J. H. Jacobs. _Parsing Techniques: A Practical Guide_. 1990.", 1, 35),
+        (2, "Грамматики как средства генерации предложений", 6, 35),
+      ],
+    );
+  }
+
+  #[test]
+  pub fn parse_outline_characters_test() {
+    let input = "😀\n=\n\n## 😀 a ##";
+    let characters = |encoding| {
+      let headings = parse_outline(input, encoding, Strategy::Bytes);
+      let range = headings[1].text;
+      (headings[0].range.end, range.start.character, range.end.character)
+    };
+
+    let end = Position { line: 1, character: 1, offset: 6 };
+    assert_eq!(characters(Encoding::Utf8), (end, 3, 9));
+    assert_eq!(characters(Encoding::Utf16), (end, 3, 7));
+    assert_eq!(characters(Encoding::Utf32), (end, 3, 6));
+  }
+
+  #[bench]
+  pub fn parse_outline_bytes_bench(b: &mut Bencher) {
+    b.iter(|| parse_outline(BENCHMARK_INPUT, Encoding::Utf16, Strategy::Bytes).len());
+  }
+
+  #[bench]
+  pub fn parse_outline_v256_bench(b: &mut Bencher) {
+    b.iter(|| parse_outline(BENCHMARK_INPUT, Encoding::Utf16, Strategy::Vector256).len());
+  }
+}
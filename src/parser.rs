@@ -0,0 +1,446 @@
+//! Common interface of the parsers, regardless of the position encoding they produce.
+
+use crate::brackets::*;
+use crate::types::*;
+
+/// Parsing strategy (the way input is traversed).
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Strategy {
+  /// Iterate over `char`s.
+  Chars,
+  /// Iterate over bytes.
+  Bytes,
+  /// Skip 16-byte blocks without lookup hits using SSE intrinsics.
+  Vector128,
+  /// Skip 32-byte blocks without lookup hits using AVX2 intrinsics.
+  Vector256,
+  /// Skip 16-byte blocks without lookup hits using portable SIMD.
+  Vector128Portable,
+  /// Jump straight to each lookup hit of 32-byte blocks using AVX2 bitmasks, counting characters between hits with
+  /// popcounts.
+  Bitmask256,
+  /// Build a structural index of lookup hits and per-block character counts in 64-byte blocks first, then walk it
+  /// without looking at any other bytes.
+  Structural,
+}
+
+impl Strategy {
+  pub const ALL: [Self; 7] = [
+    Self::Chars,
+    Self::Bytes,
+    Self::Vector128,
+    Self::Vector256,
+    Self::Vector128Portable,
+    Self::Bitmask256,
+    Self::Structural,
+  ];
+
+  #[must_use]
+  pub const fn name(self) -> &'static str {
+    match self {
+      Self::Chars => "chars",
+      Self::Bytes => "bytes",
+      Self::Vector128 => "vector128",
+      Self::Vector256 => "vector256",
+      Self::Vector128Portable => "vector128portable",
+      Self::Bitmask256 => "bitmask256",
+      Self::Structural => "structural",
+    }
+  }
+
+  #[must_use]
+  pub fn from_name(name: &str) -> Option<Self> {
+    Self::ALL.into_iter().find(|strategy| strategy.name() == name)
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct ParseOptions {
+  /// Sequences that open and close ranges (`[` and `]` by default).
+  pub delimiters: Vec<Delimiter>,
+  /// Maximum number of simultaneously open brackets. With 1 (the default) ranges aren't nested: an opening bracket
+  /// inside a range is ignored. Openers beyond the limit are ignored as well, so hostile input can't grow the stack
+  /// indefinitely.
+  pub max_depth: usize,
+  /// Whether delimiters preceded by an odd number of backslashes (e.g. `\\[`, but not `\\\\[`) are treated as literal
+  /// text, as in Markdown. Disabled by default, so a backslash is plain text unless the caller opts in.
+  pub escapes: bool,
+  /// Whether delimiters inside Markdown code spans (`` `[a]` ``) and fenced code blocks are ignored.
+  pub code: bool,
+  /// Whether brackets still open at the end of a line are reported as unclosed, so that ranges never span lines and
+  /// a stray opening bracket doesn't swallow one on the next line.
+  pub single_line: bool,
+}
+
+impl Default for ParseOptions {
+  fn default() -> Self {
+    Self { delimiters: vec![Delimiter::default()], max_depth: 1, escapes: false, code: false, single_line: false }
+  }
+}
+
+/// Parser that finds pairs of delimiters (square brackets unless configured otherwise) and reports them as ranges.
+///
+/// Implementors only differ in what `Position::character` means (see [`crate::utf32_parser`],
+/// [`crate::utf16_parser`] and [`crate::utf8_parser`]); every strategy of the same parser must produce identical
+/// ranges.
+pub trait RangeParser<'a>: Sized {
+  /// Unit of `Position::character` in the produced ranges.
+  const ENCODING: Encoding;
+
+  #[must_use]
+  fn with_options(input: &'a str, options: ParseOptions) -> Self;
+
+  #[must_use]
+  fn new(input: &'a str) -> Self {
+    Self::with_options(input, ParseOptions::default())
+  }
+
+  /// Brackets matched so far (along with their nesting).
+  fn brackets(&self) -> &Brackets;
+
+  fn parse_chars(&mut self) -> &[Range];
+  fn parse_bytes(&mut self) -> &[Range];
+  fn parse_v128(&mut self) -> &[Range];
+  fn parse_v256(&mut self) -> &[Range];
+  fn parse_v128_portable(&mut self) -> &[Range];
+  fn parse_bitmask(&mut self) -> &[Range];
+  fn parse_structural(&mut self) -> &[Range];
+
+  fn parse(&mut self, strategy: Strategy) -> &[Range] {
+    match strategy {
+      Strategy::Chars => self.parse_chars(),
+      Strategy::Bytes => self.parse_bytes(),
+      Strategy::Vector128 => self.parse_v128(),
+      Strategy::Vector256 => self.parse_v256(),
+      Strategy::Vector128Portable => self.parse_v128_portable(),
+      Strategy::Bitmask256 => self.parse_bitmask(),
+      Strategy::Structural => self.parse_structural(),
+    }
+  }
+}
+
+#[cfg(test)]
+pub mod tests {
+  use super::*;
+  use crate::tests::test_data::*;
+  use crate::utf16_parser::*;
+  use crate::utf32_parser::*;
+  use crate::utf8_parser::*;
+
+  #[test]
+  pub fn strategy_name_test() {
+    for strategy in Strategy::ALL {
+      assert_eq!(Strategy::from_name(strategy.name()), Some(strategy));
+    }
+    assert_eq!(Strategy::from_name("vector512"), None);
+  }
+
+  pub fn assert_strategies_agree<'a, P: RangeParser<'a>>(input: &'a str) {
+    let expected = P::new(input).parse_chars().to_vec();
+
+    for strategy in Strategy::ALL {
+      assert_eq!(P::new(input).parse(strategy), expected, "{}", strategy.name());
+    }
+  }
+
+  fn assert_nested_ranges<'a, P: RangeParser<'a>>(
+    input: &'a str,
+    max_depth: usize,
+    expected: &[(usize, usize, usize)],
+  ) {
+    for strategy in Strategy::ALL {
+      let mut parser = P::with_options(input, ParseOptions { max_depth, ..ParseOptions::default() });
+      parser.parse(strategy);
+
+      let brackets = parser.brackets();
+      let actual: Vec<_> = brackets
+        .ranges()
+        .iter()
+        .zip(brackets.depths())
+        .map(|(range, &depth)| (range.start.offset, range.end.offset, depth))
+        .collect();
+      assert_eq!(actual, expected, "{}", strategy.name());
+    }
+  }
+
+  #[test]
+  pub fn parse_nested_test() {
+    for (max_depth, expected) in [
+      (1, vec![(0, 6, 0), (12, 18, 0)]),
+      (2, vec![(3, 6, 1), (0, 9, 0), (14, 18, 1), (12, 19, 0)]),
+      (8, vec![(3, 6, 1), (0, 9, 0), (15, 18, 2), (14, 19, 1)]),
+    ] {
+      assert_nested_ranges::<Utf32Parser>(NESTED_INPUT, max_depth, &expected);
+      assert_nested_ranges::<Utf16Parser>(NESTED_INPUT, max_depth, &expected);
+      assert_nested_ranges::<Utf8Parser>(NESTED_INPUT, max_depth, &expected);
+    }
+
+    let mut parser = Utf32Parser::with_options(NESTED_INPUT, ParseOptions { max_depth: 8, ..ParseOptions::default() });
+    parser.parse_v128_portable();
+    let tree = parser.brackets().tree();
+    assert_eq!(tree.roots, [0, 2]);
+    assert_eq!(tree.nodes[0].children, [1]);
+    assert_eq!(tree.nodes[2].children, [3]);
+  }
+
+  fn assert_diagnostics<'a, P: RangeParser<'a>>(
+    input: &'a str,
+    max_depth: usize,
+    expected: &[(DiagnosticKind, usize)],
+  ) {
+    for strategy in Strategy::ALL {
+      let mut parser = P::with_options(input, ParseOptions { max_depth, ..ParseOptions::default() });
+      parser.parse(strategy);
+
+      let actual: Vec<_> = parser
+        .brackets()
+        .diagnostics()
+        .iter()
+        .map(|diagnostic| (diagnostic.kind, diagnostic.range.start.offset))
+        .collect();
+      assert_eq!(actual, expected, "{}", strategy.name());
+    }
+  }
+
+  #[test]
+  pub fn parse_diagnostics_test() {
+    use DiagnosticKind::*;
+
+    for (max_depth, expected) in [
+      (
+        1,
+        vec![
+          (NestingTooDeep, 3),
+          (UnexpectedClosingBracket, 8),
+          (UnexpectedClosingBracket, 10),
+          (NestingTooDeep, 14),
+          (NestingTooDeep, 15),
+          (UnexpectedClosingBracket, 18),
+        ],
+      ),
+      (8, vec![(UnexpectedClosingBracket, 10), (UnclosedBracket, 12)]),
+    ] {
+      assert_diagnostics::<Utf32Parser>(NESTED_INPUT, max_depth, &expected);
+      assert_diagnostics::<Utf16Parser>(NESTED_INPUT, max_depth, &expected);
+      assert_diagnostics::<Utf8Parser>(NESTED_INPUT, max_depth, &expected);
+    }
+  }
+
+  fn assert_delimiters<'a, P: RangeParser<'a>>(input: &'a str, expected: &[(usize, usize, usize)]) {
+    let delimiters = vec![
+      Delimiter::new(b'(', b')'),
+      Delimiter::new(b'{', b'}'),
+      Delimiter::new(b'<', b'>'),
+      Delimiter::new(b'$', b'$'),
+    ];
+
+    for strategy in Strategy::ALL {
+      let mut parser = P::with_options(
+        input,
+        ParseOptions { delimiters: delimiters.clone(), max_depth: 4, ..ParseOptions::default() },
+      );
+      parser.parse(strategy);
+
+      let brackets = parser.brackets();
+      let actual: Vec<_> = brackets
+        .ranges()
+        .iter()
+        .zip(brackets.kinds())
+        .map(|(range, &kind)| (range.start.offset, range.end.offset, kind))
+        .collect();
+      assert_eq!(actual, expected, "{}", strategy.name());
+      assert_eq!(brackets.diagnostics(), [], "{}", strategy.name());
+    }
+  }
+
+  #[test]
+  pub fn parse_delimiters_test() {
+    let input = "[f(x)] = {a, <b>} where $y = (1)$ and [$z$]";
+    let expected = [(2, 5, 0), (13, 16, 2), (9, 17, 1), (29, 32, 0), (24, 33, 3), (39, 42, 3)];
+
+    assert_delimiters::<Utf32Parser>(input, &expected);
+    assert_delimiters::<Utf16Parser>(input, &expected);
+    assert_delimiters::<Utf8Parser>(input, &expected);
+  }
+
+  fn assert_escapes<'a, P: RangeParser<'a>>(input: &'a str, escapes: bool, expected: &[(usize, usize)]) {
+    for strategy in Strategy::ALL {
+      let mut parser = P::with_options(input, ParseOptions { escapes, ..ParseOptions::default() });
+      let actual: Vec<_> = parser.parse(strategy).iter().map(|range| (range.start.offset, range.end.offset)).collect();
+      assert_eq!(actual, expected, "{}", strategy.name());
+    }
+  }
+
+  #[test]
+  pub fn parse_escapes_test() {
+    let input = r"\[a\] [b\\] \\[c] \\\[d]";
+
+    for (escapes, expected) in [(true, vec![(6, 11), (14, 17)]), (false, vec![(1, 5), (6, 11), (14, 17), (21, 24)])] {
+      assert_escapes::<Utf32Parser>(input, escapes, &expected);
+      assert_escapes::<Utf16Parser>(input, escapes, &expected);
+      assert_escapes::<Utf8Parser>(input, escapes, &expected);
+    }
+  }
+
+  fn assert_code<'a, P: RangeParser<'a>>(input: &'a str, expected: &[Range]) {
+    for strategy in Strategy::ALL {
+      let mut parser = P::with_options(input, ParseOptions { code: true, ..ParseOptions::default() });
+      assert_eq!(parser.parse(strategy), expected, "{}", strategy.name());
+    }
+  }
+
+  #[test]
+  pub fn parse_code_test() {
+    let input = "[a] `[b]`\r\n~~~ [c]\r\n[d]\r\n~~~\r\n``[e]`` [f]";
+    let expected = [
+      Range { start: Position::default(), end: Position { line: 0, character: 3, offset: 3 } },
+      Range {
+        start: Position { line: 4, character: 8, offset: 38 },
+        end: Position { line: 4, character: 11, offset: 41 },
+      },
+    ];
+
+    assert_code::<Utf32Parser>(input, &expected);
+    assert_code::<Utf16Parser>(input, &expected);
+    assert_code::<Utf8Parser>(input, &expected);
+  }
+
+  pub fn assert_options_agree<'a, P: RangeParser<'a>>(input: &'a str, options: &ParseOptions) {
+    let mut reference = P::with_options(input, options.clone());
+    let expected = reference.parse_chars().to_vec();
+    let expected_diagnostics = reference.brackets().diagnostics().to_vec();
+
+    for strategy in Strategy::ALL {
+      let mut parser = P::with_options(input, options.clone());
+      assert_eq!(parser.parse(strategy), expected, "{}", strategy.name());
+      assert_eq!(parser.brackets().diagnostics(), expected_diagnostics, "{}", strategy.name());
+      assert!(parser.brackets().depths().iter().all(|&depth| depth < options.max_depth), "{}", strategy.name());
+    }
+  }
+
+  pub fn assert_options_property<'a, P: RangeParser<'a>>(
+    input: &'a str,
+    max_depth: usize,
+    escapes: bool,
+    code: bool,
+    single_line: bool,
+  ) {
+    let delimiters = vec![Delimiter::new(b'[', b']'), Delimiter::new(b'(', b')'), Delimiter::new(b'$', b'$')];
+    assert_options_agree::<P>(input, &ParseOptions { delimiters, max_depth, escapes, code, single_line });
+  }
+
+  pub fn assert_sequences_property<'a, P: RangeParser<'a>>(input: &'a str, max_depth: usize) {
+    assert_options_agree::<P>(
+      input,
+      &ParseOptions { delimiters: sequence_delimiters(), max_depth, ..ParseOptions::default() },
+    );
+  }
+
+  pub fn assert_line_endings<'a, P: RangeParser<'a>>() {
+    let position = |line, character, offset| Position { line, character, offset };
+
+    for strategy in Strategy::ALL {
+      let ranges = P::new(MIXED_LINE_ENDINGS_INPUT).parse(strategy).to_vec();
+
+      assert_eq!(
+        ranges,
+        vec![
+          Range { start: position(0, 0, 0), end: position(0, 3, 3) },
+          Range { start: position(1, 0, 5), end: position(1, 3, 8) },
+          Range { start: position(2, 0, 9), end: position(2, 3, 12) },
+          Range { start: position(3, 0, 13), end: position(3, 3, 16) },
+        ],
+        "{}",
+        strategy.name()
+      );
+    }
+  }
+
+  /// Inputs are leaked, so that they live as long as the parsers borrowing them need.
+  pub fn assert_crlf_block_boundary<'a, P: RangeParser<'a>>() {
+    // '\r' is the last byte of a 16- or 32-byte block and '\n' is the first byte of the next one.
+    for width in [15, 31] {
+      let input = format!("[{}\r\n]", "-".repeat(width - 1)).leak();
+      for strategy in Strategy::ALL {
+        let ranges = P::new(input).parse(strategy).to_vec();
+        let end = Position { line: 1, character: 1, offset: width + 3 };
+        assert_eq!(ranges, vec![Range { start: Position::default(), end }], "{}", strategy.name());
+      }
+
+      let input = format!("{}\r\n[a]", "-".repeat(width)).leak();
+      for strategy in Strategy::ALL {
+        let ranges = P::new(input).parse(strategy).to_vec();
+        let start = Position { line: 1, character: 0, offset: width + 2 };
+        let end = Position { line: 1, character: 3, offset: width + 5 };
+        assert_eq!(ranges, vec![Range { start, end }], "{}", strategy.name());
+      }
+    }
+  }
+
+  pub fn assert_sequence_block_boundary<'a, P: RangeParser<'a>>() {
+    // The last byte of a 16- or 32-byte block starts a sequence that ends in the next one.
+    for width in [15, 31] {
+      for (input, expected) in [
+        (format!("[[{}]]$$x$$", "-".repeat(width - 2)), [(0, width + 2), (width + 2, width + 7)]),
+        (format!("{}$$x$$ ```y```", "-".repeat(width)), [(width, width + 5), (width + 6, width + 13)]),
+      ] {
+        let input = input.leak();
+        for strategy in Strategy::ALL {
+          let options = ParseOptions { delimiters: sequence_delimiters(), max_depth: 2, ..ParseOptions::default() };
+          let mut parser = P::with_options(input, options);
+          let actual: Vec<_> =
+            parser.parse(strategy).iter().map(|range| (range.start.offset, range.end.offset)).collect();
+          assert_eq!(actual, expected, "{}", strategy.name());
+          assert_eq!(parser.brackets().diagnostics(), [], "{}", strategy.name());
+        }
+      }
+    }
+  }
+
+  pub fn assert_escape_block_boundary<'a, P: RangeParser<'a>>() {
+    // A backslash is the last byte of a 16- or 32-byte block and the bracket it escapes is the first byte of the next.
+    for width in [15, 31] {
+      for (input, expected) in [
+        (format!("{}\\[a] [b]", "-".repeat(width)), [(width + 5, width + 8)]),
+        (format!("{}\\\\[a]", "-".repeat(width - 1)), [(width + 1, width + 4)]),
+      ] {
+        let input = input.leak();
+        for strategy in Strategy::ALL {
+          let actual: Vec<_> = P::with_options(input, ParseOptions { escapes: true, ..ParseOptions::default() })
+            .parse(strategy)
+            .iter()
+            .map(|range| (range.start.offset, range.end.offset))
+            .collect();
+          assert_eq!(actual, expected, "{}", strategy.name());
+        }
+      }
+    }
+  }
+
+  pub fn assert_code_block_boundary<'a, P: RangeParser<'a>>() {
+    // A backtick run or a fence starts at the last byte of a 16- or 32-byte block.
+    for width in [15, 31] {
+      for (input, expected) in [
+        (format!("{}``[a]`` [b]", "-".repeat(width - 1)), [(width + 7, width + 10)]),
+        (format!("{}\n```\n[a]\n```\n[b]", "-".repeat(width - 1)), [(width + 12, width + 15)]),
+      ] {
+        let input = input.leak();
+        for strategy in Strategy::ALL {
+          let mut parser = P::with_options(input, ParseOptions { code: true, ..ParseOptions::default() });
+          let actual: Vec<_> =
+            parser.parse(strategy).iter().map(|range| (range.start.offset, range.end.offset)).collect();
+          assert_eq!(actual, expected, "{}", strategy.name());
+        }
+      }
+    }
+  }
+
+  #[test]
+  pub fn parse_strategies_test() {
+    for input in [GIBBERISH, SHORT_MULTILINE_INPUT, LONG_MULTILINE_INPUT, DENSE_BRACKETS_INPUT] {
+      assert_strategies_agree::<Utf32Parser>(input);
+      assert_strategies_agree::<Utf16Parser>(input);
+      assert_strategies_agree::<Utf8Parser>(input);
+    }
+  }
+}
@@ -0,0 +1,136 @@
+//! Structural index for two-stage parsing, as in simdjson: stage 1 finds structural bytes (lookup hits) and character
+//! boundaries with SIMD, and stage 2 walks the index with the bracket state machine, never looking at other bytes.
+
+use std::arch::x86_64::*;
+
+use crate::lookup::*;
+use crate::utf8::*;
+
+/// Continuation bytes are neither structural nor character starts, so they pad the last block.
+const PADDING: u8 = 0b1000_0000;
+
+/// Offsets of structural bytes of the input, and character boundaries of each of its 64-byte blocks. Character and
+/// UTF-16 code unit counts up to any offset take a block lookup and a popcount.
+#[derive(Clone, Debug)]
+pub struct StructuralIndex {
+  offsets: Vec<u32>,
+  /// Bytes that start characters, for each block.
+  starts: Vec<u64>,
+  /// Bytes that start 4-byte characters (the ones that take 2 UTF-16 code units), for each block.
+  four_byte_starts: Vec<u64>,
+  /// Number of characters before each block (and after the last one).
+  characters: Vec<usize>,
+  /// Number of UTF-16 code units before each block (and after the last one).
+  utf16_units: Vec<usize>,
+}
+
+impl StructuralIndex {
+  /// Stage 1: scans the input in 64-byte blocks.
+  ///
+  /// # Panics
+  ///
+  /// Panics if the input is longer than 4 GiB, as offsets are 32-bit.
+  #[must_use]
+  pub fn new(input: &str, lookup: &Lookup) -> Self {
+    let bytes = input.as_bytes();
+    assert!(u32::try_from(bytes.len()).is_ok(), "input is too long for a structural index");
+
+    let block_count = bytes.len() / 64 + 1;
+    let mut index = Self {
+      offsets: vec![],
+      starts: Vec::with_capacity(block_count),
+      four_byte_starts: Vec::with_capacity(block_count),
+      characters: vec![0],
+      utf16_units: vec![0],
+    };
+
+    let mut offset = 0;
+    while offset + 63 < bytes.len() {
+      index.push_block(lookup, &bytes[offset..offset + 64], offset);
+      offset += 64;
+    }
+    // The last block is always there (even if it's empty), so that counts up to the end of the input are defined.
+    let mut block = [PADDING; 64];
+    block[..bytes.len() - offset].copy_from_slice(&bytes[offset..]);
+    index.push_block(lookup, &block, offset);
+
+    index
+  }
+
+  /// Offsets of structural bytes in ascending order.
+  #[must_use]
+  pub fn offsets(&self) -> &[u32] {
+    &self.offsets
+  }
+
+  /// Number of characters before the offset.
+  #[inline]
+  #[must_use]
+  pub fn characters(&self, offset: usize) -> usize {
+    let (block, bits) = (offset / 64, (1u64 << (offset % 64)) - 1);
+    self.characters[block] + (self.starts[block] & bits).count_ones() as usize
+  }
+
+  /// Number of UTF-16 code units before the offset.
+  #[inline]
+  #[must_use]
+  pub fn utf16_units(&self, offset: usize) -> usize {
+    let (block, bits) = (offset / 64, (1u64 << (offset % 64)) - 1);
+    let units = (self.starts[block] & bits).count_ones() + (self.four_byte_starts[block] & bits).count_ones();
+    self.utf16_units[block] + units as usize
+  }
+
+  fn push_block(&mut self, lookup: &Lookup, block: &[u8], offset: usize) {
+    let (low, high) =
+      unsafe { (_mm256_loadu_si256(block.as_ptr().cast()), _mm256_loadu_si256(block[32..].as_ptr().cast())) };
+    let join = |low: u32, high: u32| u64::from(low) | u64::from(high) << 32;
+
+    let mut hits = join(lookup.mask_v256(low), lookup.mask_v256(high));
+    while hits != 0 {
+      self.offsets.push((offset + hits.trailing_zeros() as usize) as u32);
+      hits &= hits - 1;
+    }
+
+    let starts = join(character_starts_v256(low), character_starts_v256(high));
+    let four_byte_starts = join(four_byte_starts_v256(low), four_byte_starts_v256(high));
+    let units = starts.count_ones() + four_byte_starts.count_ones();
+    self.starts.push(starts);
+    self.four_byte_starts.push(four_byte_starts);
+    self.characters.push(self.characters[self.characters.len() - 1] + starts.count_ones() as usize);
+    self.utf16_units.push(self.utf16_units[self.utf16_units.len() - 1] + units as usize);
+  }
+}
+
+#[cfg(test)]
+pub mod tests {
+  extern crate test;
+  use test::bench::Bencher;
+
+  use super::*;
+  use crate::parser::Strategy;
+  use crate::tests::test_data::*;
+
+  #[test]
+  pub fn structural_index_test() {
+    let lookup = Lookup::new(*b"[]\n\r");
+    let emoji = format!("{}[😀]\n{}[ы]", "-".repeat(62), "😀".repeat(20));
+
+    for input in ["", GIBBERISH, SHORT_MULTILINE_INPUT, LONG_MULTILINE_INPUT, DENSE_BRACKETS_INPUT, &emoji] {
+      let index = StructuralIndex::new(input, &lookup);
+
+      let offsets: Vec<_> = index.offsets().iter().map(|&offset| offset as usize).collect();
+      assert_eq!(offsets, lookup.find_all(input, Strategy::Bytes));
+
+      for (offset, _) in input.char_indices().chain([(input.len(), ' ')]) {
+        assert_eq!(index.characters(offset), input[..offset].chars().count());
+        assert_eq!(index.utf16_units(offset), input[..offset].encode_utf16().count());
+      }
+    }
+  }
+
+  #[bench]
+  pub fn structural_index_bench(b: &mut Bencher) {
+    let lookup = Lookup::new(*b"[]\n\r");
+    b.iter(|| StructuralIndex::new(BENCHMARK_INPUT, &lookup).offsets().len());
+  }
+}
@@ -1,3 +1,3 @@
-#[cfg(not(miri))]
+#[cfg(all(feature = "simdutf", not(miri)))]
 mod simdutf_tests;
 pub mod test_data;
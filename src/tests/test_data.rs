@@ -1,3 +1,5 @@
+use crate::brackets::Delimiter;
+
 pub static BENCHMARK_INPUT: &str = LONG_MULTILINE_INPUT;
 
 pub static GIBBERISH: &str = "АaaAa0AAAaА0aАAАaAaАAAAaaaAA0aa]aaaaaaАaaA0AAa]00AaA]]aaА0aA]АaА]АaA00]a0А]]0a0АА]0AaaАa0]aaАA0AА0A0AAAAaАAАААAAaА]]a0]aaA]0A0aAaAaAaaaaА0a0A]]A0a0a]aА0AaAAaa]]AaA0AААAa]]AAaА0AA]0АaAa0AAАААaA]]AAaАA0A0А00a0aaAААA0a0AАaA]aАa0A]0a0AАaAa0aА]0АAAa]А]AА]]AaA0AaA0000aaАa]AaAaA]aAAAА]aAA[]AAaaAaa0Aaaaa]E]";
@@ -10,6 +12,19 @@ pub static LONG_ASCII_INPUT: &str =
 pub static LONG_UNICODE_INPUT: &str =
   "йцуке йцуке йцуке йцуке йцуке йцуке [фыв фыв фыв] ячсми ячсми ячсми ячсми ячсми ячсми";
 
+pub static NESTED_INPUT: &str = "[a [b] c] ]x[ [[d]]";
+
+/// Short lines with a bracket every few characters, mixing 1- to 4-byte characters, so that almost every 16- or 32-byte
+/// block has lookup hits.
+pub static DENSE_BRACKETS_INPUT: &str = "[a] [б] [[в]] [😀] [x [y] z] [ё] [] [[]] [ф]ы[в] [🎲 [к]]
+- [[Мышеловка]] [[Кнопка]] [🚀] [a][b][c] [д] [е [ж [з]]] [😀😀] [и]
+[1] [2] [3] [щ] [🙂] [[x]] [[ы]] [э][ю][я] [ab] [вг] [[🎲]] [][][] [q]
+]] [[ [a] [ь [ъ]] [😀 x] [й] [ц] [у] [к] [е] [н] [г] [ш] [щ] [з] [х]
+[Кампании] [Персонажи] [Правила] [😀] [[Бой]] [Космический бой] [a]
+";
+
+pub static MIXED_LINE_ENDINGS_INPUT: &str = "[a]\r\n[b]\r[c]\n[d]";
+
 pub static SHORT_MULTILINE_INPUT: &str = "
 # Starfinder
 
@@ -68,3 +83,15 @@ pub static LONG_MULTILINE_INPUT: &str = "
 
 **Определение**: Нетерминальный символ, язык которого содержит $\\epsilon$, называется _обнуляемым (nullable)_.
 ";
+
+/// Delimiters with overlapping multi-byte sequences.
+pub fn sequence_delimiters() -> Vec<Delimiter> {
+  vec![
+    Delimiter::sequence(b"[[", b"]]"),
+    Delimiter::new(b'[', b']'),
+    Delimiter::sequence(b"](", b")"),
+    Delimiter::sequence(b"$$", b"$$"),
+    Delimiter::new(b'$', b'$'),
+    Delimiter::sequence(b"```", b"```"),
+  ]
+}
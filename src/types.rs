@@ -1,3 +1,5 @@
+use crate::utf8::*;
+
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
 pub struct Position {
   pub line: usize,
@@ -10,3 +12,54 @@ pub struct Range {
   pub start: Position,
   pub end: Position,
 }
+
+/// Unit that `Position::character` is measured in.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoding {
+  /// Bytes (LSP `utf-8`).
+  Utf8,
+  /// UTF-16 code units (LSP `utf-16`).
+  Utf16,
+  /// Characters (LSP `utf-32`).
+  Utf32,
+}
+
+impl Encoding {
+  /// Width of a character in code units of the encoding.
+  #[inline]
+  #[must_use]
+  pub const fn width(self, char: char) -> usize {
+    match self {
+      Self::Utf8 => char.len_utf8(),
+      Self::Utf16 => char.len_utf16(),
+      Self::Utf32 => 1,
+    }
+  }
+
+  /// Moves `position` forward to `offset` of the input, which must be a character boundary.
+  #[must_use]
+  pub fn advance(self, input: &str, mut position: Position, offset: usize) -> Position {
+    let bytes = input.as_bytes();
+
+    for char in input[position.offset..offset].chars() {
+      let char_offset = position.offset;
+      position.offset += char.len_utf8();
+
+      match char {
+        '\r' => {
+          position.line += 1;
+          position.character = 0;
+        }
+        '\n' => {
+          if !is_crlf_line_feed(bytes, char_offset) {
+            position.line += 1;
+          }
+          position.character = 0;
+        }
+        _ => position.character += self.width(char),
+      }
+    }
+
+    position
+  }
+}
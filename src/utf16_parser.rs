@@ -1,7 +1,12 @@
 //! Parsers that produce offsets based on UTF-16 code points (LSP-compatible).
 
-use std::simd::{u8x16, SimdPartialEq};
+use std::arch::x86_64::*;
+use std::simd::u8x16;
 
+use crate::brackets::*;
+use crate::lookup::*;
+use crate::parser::*;
+use crate::structural::*;
 use crate::types::*;
 use crate::utf8::*;
 
@@ -11,19 +16,32 @@ pub struct Utf16Parser<'a> {
   line: usize,
   character: usize,
   character_offset: usize,
-  range_start: Option<Position>,
-  ranges: Vec<Range>,
+  brackets: Brackets,
+  lookup: Lookup,
+  /// Picked once, so that counting between brackets doesn't detect CPU features every time.
+  utf16_length: Utf16Counter,
 }
 
-impl<'a> Utf16Parser<'a> {
-  #[must_use]
-  pub const fn new(input: &'a str) -> Self {
-    Self { input, offset: 0, line: 0, character: 0, character_offset: 0, range_start: None, ranges: vec![] }
+impl<'a> RangeParser<'a> for Utf16Parser<'a> {
+  const ENCODING: Encoding = Encoding::Utf16;
+
+  fn with_options(input: &'a str, options: ParseOptions) -> Self {
+    let brackets = Brackets::new(options);
+    let lookup = Lookup::new(brackets.leading_bytes().chain(*b"\n\r"));
+    let utf16_length = utf16_length_counter();
+    Self { input, offset: 0, line: 0, character: 0, character_offset: 0, brackets, lookup, utf16_length }
   }
 
-  pub fn parse_chars(&mut self) -> &[Range] {
+  fn brackets(&self) -> &Brackets {
+    &self.brackets
+  }
+
+  fn parse_chars(&mut self) -> &[Range] {
     let mut line = 0;
     let mut character = 0;
+    let mut previous_char = None;
+    // Characters that belong to a delimiter sequence that was already handled.
+    let mut skip = 0;
 
     for char in self.input.chars() {
       let previous_position = Position { line, character, offset: self.offset };
@@ -31,98 +49,204 @@ impl<'a> Utf16Parser<'a> {
       character += char.len_utf16();
       self.offset += char.len_utf8();
 
-      match (char, self.range_start) {
-        (']', Some(start)) => {
-          self.ranges.push(Range { start, end: Position { line, character, offset: self.offset } });
-          self.range_start = None;
-        }
-        ('[', None) => {
-          self.range_start = Some(previous_position);
-        }
-        ('\n', _) => {
+      match char {
+        '\r' => {
           line += 1;
           character = 0;
         }
+        '\n' => {
+          if previous_char != Some('\r') {
+            line += 1;
+          }
+          character = 0;
+        }
+        _ if skip > 0 => skip -= 1,
+        _ if char.is_ascii() => {
+          skip = self.brackets.handle(self.input.as_bytes(), previous_position).saturating_sub(1);
+        }
         _ => {}
       }
+
+      previous_char = Some(char);
     }
-    &self.ranges
+    self.brackets.finish();
+    self.brackets.ranges()
   }
 
-  pub fn parse_bytes(&mut self) -> &[Range] {
+  fn parse_bytes(&mut self) -> &[Range] {
     self.parse_bytes_limited(self.input.len());
-    &self.ranges
+    self.brackets.finish();
+    self.brackets.ranges()
   }
 
-  pub fn parse_bytes_limited(&mut self, limit: usize) {
+  fn parse_v128(&mut self) -> &[Range] {
     let bytes = self.input.as_bytes();
 
-    let max_offset = (self.offset + limit).min(bytes.len());
+    while self.offset + 15 < bytes.len() {
+      let bytes_vec = unsafe { _mm_loadu_si128((bytes[self.offset..].as_ptr()).cast()) };
 
-    while self.offset < max_offset {
-      let &byte = unsafe { bytes.get_unchecked(self.offset) };
+      if self.lookup.hit_v128(bytes_vec) {
+        self.parse_bytes_limited(16);
+      } else {
+        self.offset += 16;
+      }
+    }
 
-      let character_width = get_character_width(byte);
-      self.offset += character_width;
+    self.parse_bytes();
 
-      match (byte, self.range_start) {
-        (b'\n', _) => {
-          self.line += 1;
-          self.character_offset = self.offset;
-          self.character = 0;
-        }
-        (b'[', None) => {
-          self.character += unsafe { simdutf::count_utf16_from_utf8(&bytes[self.character_offset..self.offset - 1]) };
-          self.character_offset = self.offset - 1;
-          self.range_start = Some(Position { line: self.line, character: self.character, offset: self.offset - 1 });
-        }
-        (b']', Some(start)) => {
-          self.character += unsafe { simdutf::count_utf16_from_utf8(&bytes[self.character_offset..self.offset]) };
-          self.character_offset = self.offset;
-          self
-            .ranges
-            .push(Range { start, end: Position { line: self.line, character: self.character, offset: self.offset } });
-          self.range_start = None;
-        }
-        _ => {}
+    self.brackets.ranges()
+  }
+
+  fn parse_v256(&mut self) -> &[Range] {
+    let bytes = self.input.as_bytes();
+
+    while self.offset + 31 < bytes.len() {
+      let bytes_vec = unsafe { _mm256_loadu_si256((bytes[self.offset..].as_ptr()).cast()) };
+
+      if self.lookup.hit_v256(bytes_vec) {
+        self.parse_bytes_limited(32);
+      } else {
+        self.offset += 32;
       }
     }
+
+    self.parse_bytes();
+
+    self.brackets.ranges()
   }
 
-  pub fn parse_v128_portable(&mut self) -> &[Range] {
+  fn parse_v128_portable(&mut self) -> &[Range] {
     let bytes = self.input.as_bytes();
 
     while self.offset + 15 < bytes.len() {
       let bytes_vec = u8x16::from_slice(&bytes[self.offset..]);
 
-      let lookup = if self.range_start.is_some() {
-        // Lookup: ']', '\n'
-        let eq_93 = bytes_vec.simd_eq(u8x16::splat(b']'));
-        let eq_10 = bytes_vec.simd_eq(u8x16::splat(b'\n'));
-
-        eq_93 | eq_10
+      if self.lookup.hit_v128_portable(bytes_vec) {
+        self.parse_bytes_limited(16);
       } else {
-        // Lookup: '[', '\n'
+        self.offset += 16;
+      }
+    }
 
-        let eq_91 = bytes_vec.simd_eq(u8x16::splat(b'['));
-        let eq_10 = bytes_vec.simd_eq(u8x16::splat(b'\n'));
+    self.parse_bytes();
+
+    self.brackets.ranges()
+  }
+
+  fn parse_bitmask(&mut self) -> &[Range] {
+    let bytes = self.input.as_bytes();
 
-        eq_91 | eq_10
+    while self.offset + 31 < bytes.len() {
+      let block = self.offset;
+      let bytes_vec = unsafe { _mm256_loadu_si256((bytes[block..].as_ptr()).cast()) };
+      let starts = character_starts_v256(bytes_vec);
+      let four_byte_starts = four_byte_starts_v256(bytes_vec);
+      // 4-byte characters take 2 code units.
+      let units = |start, end| {
+        let bits = bits_between(start, end);
+        ((starts & bits).count_ones() + (four_byte_starts & bits).count_ones()) as usize
       };
 
-      if lookup.any() {
-        self.parse_bytes_limited(16);
-      } else {
-        self.offset += 16;
+      // Bytes skipped past the previous block are the rest of a delimiter sequence, which is ASCII.
+      self.character += block - self.character_offset;
+      self.character_offset = block;
+
+      let mut hits = self.lookup.mask_v256(bytes_vec);
+      while hits != 0 {
+        let hit = hits.trailing_zeros() as usize;
+        self.character += units(self.character_offset - block, hit);
+        self.character_offset = block + hit;
+        self.offset = block + hit;
+        self.parse_bytes_limited(1);
+        hits &= !bits_between(0, (self.offset - block).min(32));
+      }
+      self.character += units(self.character_offset - block, 32);
+      self.character_offset = block + 32;
+      self.offset = self.offset.max(block + 32);
+    }
+
+    // The block boundary can split a character, and the rest is counted lazily starting from a character boundary.
+    while self.character_offset < bytes.len() && is_continuation_byte(bytes[self.character_offset]) {
+      self.character_offset -= 1;
+      if !is_continuation_byte(bytes[self.character_offset]) {
+        self.character -= 1 + usize::from(bytes[self.character_offset] >= 0b1111_0000);
       }
     }
 
     self.parse_bytes();
 
-    &self.ranges
+    self.brackets.ranges()
+  }
+
+  fn parse_structural(&mut self) -> &[Range] {
+    let index = StructuralIndex::new(self.input, &self.lookup);
+
+    for &offset in index.offsets() {
+      let offset = offset as usize;
+      // Offsets before the current one belong to an already handled delimiter sequence.
+      if offset >= self.offset {
+        self.character += index.utf16_units(offset) - index.utf16_units(self.character_offset);
+        self.character_offset = offset;
+        self.offset = offset;
+        self.parse_bytes_limited(1);
+      }
+    }
+
+    self.brackets.finish();
+    self.brackets.ranges()
   }
 }
 
+impl<'a> Utf16Parser<'a> {
+  pub fn parse_bytes_limited(&mut self, limit: usize) {
+    let bytes = self.input.as_bytes();
+
+    let max_offset = (self.offset + limit).min(bytes.len());
+
+    while self.offset < max_offset {
+      let &byte = unsafe { bytes.get_unchecked(self.offset) };
+
+      let character_width = get_character_width(byte);
+      self.offset += character_width;
+
+      match byte {
+        b'\r' => {
+          self.line += 1;
+          self.character_offset = self.offset;
+          self.character = 0;
+        }
+        b'\n' => {
+          if !is_crlf_line_feed(bytes, self.offset - 1) {
+            self.line += 1;
+          }
+          self.character_offset = self.offset;
+          self.character = 0;
+        }
+        _ if self.brackets.is_delimiter(byte) => {
+          let offset = self.offset - 1;
+          self.character += (self.utf16_length)(&bytes[self.character_offset..offset]);
+          self.character_offset = offset;
+          let position = Position { line: self.line, character: self.character, offset };
+          self.offset += self.brackets.handle(bytes, position).saturating_sub(1);
+        }
+        _ => {}
+      }
+    }
+  }
+}
+
+/// Counter of the UTF-16 code units that UTF-8 bytes take.
+#[cfg(not(feature = "simdutf"))]
+fn utf16_length_counter() -> Utf16Counter {
+  utf16_units_counter()
+}
+
+/// Counter of the UTF-16 code units that UTF-8 bytes take, with simdutf.
+#[cfg(feature = "simdutf")]
+fn utf16_length_counter() -> Utf16Counter {
+  |bytes| unsafe { simdutf::count_utf16_from_utf8(bytes) }
+}
+
 #[cfg(test)]
 pub mod tests {
   extern crate test;
@@ -131,22 +255,29 @@ pub mod tests {
   use proptest::prelude::*;
 
   use super::*;
+  use crate::parser::tests::*;
   use crate::tests::test_data::*;
 
   proptest! {
     #[test]
-    fn parse_property_test(s in "[0-9a-zA-Zа-яА-Я\\[\\]]{300}") {
-      let ranges1 = Utf16Parser::new(s.as_str()).parse_chars().to_vec();
-      let ranges2 = Utf16Parser::new(s.as_str()).parse_bytes().to_vec();
-      let ranges3 = Utf16Parser::new(s.as_str()).parse_v128_portable().to_vec();
+    fn parse_property_test(s in "[0-9a-zA-Zа-яА-Я\\[\\]\\n\\r]{300}") {
+      assert_strategies_agree::<Utf16Parser>(&s);
+    }
 
-      assert_eq!(ranges1.len(), ranges2.len());
-      assert_eq!(ranges2.len(), ranges3.len());
+    #[test]
+    fn parse_options_property_test(
+      s in "[0-9a-zA-Zа-яА-Я \\[\\]()$`~\\\\\\n\\r]{300}",
+      max_depth in 1..8usize,
+      escapes in any::<bool>(),
+      code in any::<bool>(),
+      single_line in any::<bool>(),
+    ) {
+      assert_options_property::<Utf16Parser>(&s, max_depth, escapes, code, single_line);
+    }
 
-      for i in 0..ranges1.len() {
-        assert_eq!(ranges1[i], ranges2[i]);
-        assert_eq!(ranges2[i], ranges3[i]);
-      }
+    #[test]
+    fn parse_sequences_property_test(s in "[0-9a-zA-Zа-яА-Я\\[\\]()$`\\\\\\n\\r]{300}", max_depth in 1..8usize) {
+      assert_sequences_property::<Utf16Parser>(&s, max_depth);
     }
   }
 
@@ -154,9 +285,11 @@ pub mod tests {
   pub fn parse_small_ascii_test() {
     let ranges1 = Utf16Parser::new(SHORT_ASCII_INPUT).parse_chars().to_vec();
     let ranges2 = Utf16Parser::new(SHORT_ASCII_INPUT).parse_bytes().to_vec();
-    let ranges3 = Utf16Parser::new(SHORT_ASCII_INPUT).parse_v128_portable().to_vec();
+    let ranges3 = Utf16Parser::new(SHORT_ASCII_INPUT).parse_v128().to_vec();
+    let ranges4 = Utf16Parser::new(SHORT_ASCII_INPUT).parse_v256().to_vec();
+    let ranges5 = Utf16Parser::new(SHORT_ASCII_INPUT).parse_v128_portable().to_vec();
 
-    for ranges in vec![ranges1, ranges2, ranges3] {
+    for ranges in vec![ranges1, ranges2, ranges3, ranges4, ranges5] {
       assert_eq!(ranges.len(), 1);
       assert_eq!(
         ranges[0],
@@ -172,9 +305,11 @@ pub mod tests {
   pub fn parse_small_unicode_test() {
     let ranges1 = Utf16Parser::new(SHORT_UNICODE_INPUT).parse_chars().to_vec();
     let ranges2 = Utf16Parser::new(SHORT_UNICODE_INPUT).parse_bytes().to_vec();
-    let ranges3 = Utf16Parser::new(SHORT_UNICODE_INPUT).parse_v128_portable().to_vec();
+    let ranges3 = Utf16Parser::new(SHORT_UNICODE_INPUT).parse_v128().to_vec();
+    let ranges4 = Utf16Parser::new(SHORT_UNICODE_INPUT).parse_v256().to_vec();
+    let ranges5 = Utf16Parser::new(SHORT_UNICODE_INPUT).parse_v128_portable().to_vec();
 
-    for ranges in vec![ranges1, ranges2, ranges3] {
+    for ranges in vec![ranges1, ranges2, ranges3, ranges4, ranges5] {
       assert_eq!(ranges.len(), 1);
       assert_eq!(
         ranges[0],
@@ -190,9 +325,11 @@ pub mod tests {
   pub fn medium_ascii_test() {
     let ranges1 = Utf16Parser::new(LONG_ASCII_INPUT).parse_chars().to_vec();
     let ranges2 = Utf16Parser::new(LONG_ASCII_INPUT).parse_bytes().to_vec();
-    let ranges3 = Utf16Parser::new(LONG_ASCII_INPUT).parse_v128_portable().to_vec();
+    let ranges3 = Utf16Parser::new(LONG_ASCII_INPUT).parse_v128().to_vec();
+    let ranges4 = Utf16Parser::new(LONG_ASCII_INPUT).parse_v256().to_vec();
+    let ranges5 = Utf16Parser::new(LONG_ASCII_INPUT).parse_v128_portable().to_vec();
 
-    for ranges in vec![ranges1, ranges2, ranges3] {
+    for ranges in vec![ranges1, ranges2, ranges3, ranges4, ranges5] {
       assert_eq!(ranges.len(), 1);
       assert_eq!(
         ranges[0],
@@ -208,9 +345,11 @@ pub mod tests {
   pub fn medium_unicode_test() {
     let ranges1 = Utf16Parser::new(LONG_UNICODE_INPUT).parse_chars().to_vec();
     let ranges2 = Utf16Parser::new(LONG_UNICODE_INPUT).parse_bytes().to_vec();
-    let ranges3 = Utf16Parser::new(LONG_UNICODE_INPUT).parse_v128_portable().to_vec();
+    let ranges3 = Utf16Parser::new(LONG_UNICODE_INPUT).parse_v128().to_vec();
+    let ranges4 = Utf16Parser::new(LONG_UNICODE_INPUT).parse_v256().to_vec();
+    let ranges5 = Utf16Parser::new(LONG_UNICODE_INPUT).parse_v128_portable().to_vec();
 
-    for ranges in vec![ranges1, ranges2, ranges3] {
+    for ranges in vec![ranges1, ranges2, ranges3, ranges4, ranges5] {
       assert_eq!(ranges.len(), 1);
       assert_eq!(
         ranges[0],
@@ -226,14 +365,20 @@ pub mod tests {
   pub fn short_multiline_test() {
     let ranges1 = Utf16Parser::new(SHORT_MULTILINE_INPUT).parse_chars().to_vec();
     let ranges2 = Utf16Parser::new(SHORT_MULTILINE_INPUT).parse_bytes().to_vec();
-    let ranges3 = Utf16Parser::new(SHORT_MULTILINE_INPUT).parse_v128_portable().to_vec();
+    let ranges3 = Utf16Parser::new(SHORT_MULTILINE_INPUT).parse_v128().to_vec();
+    let ranges4 = Utf16Parser::new(SHORT_MULTILINE_INPUT).parse_v256().to_vec();
+    let ranges5 = Utf16Parser::new(SHORT_MULTILINE_INPUT).parse_v128_portable().to_vec();
 
     assert_eq!(ranges1.len(), ranges2.len());
     assert_eq!(ranges2.len(), ranges3.len());
+    assert_eq!(ranges3.len(), ranges4.len());
+    assert_eq!(ranges4.len(), ranges5.len());
 
     for i in 0..ranges1.len() {
       assert_eq!(ranges1[i], ranges2[i]);
       assert_eq!(ranges2[i], ranges3[i]);
+      assert_eq!(ranges3[i], ranges4[i]);
+      assert_eq!(ranges4[i], ranges5[i]);
     }
   }
 
@@ -241,17 +386,48 @@ pub mod tests {
   pub fn long_multiline_test() {
     let ranges1 = Utf16Parser::new(LONG_MULTILINE_INPUT).parse_chars().to_vec();
     let ranges2 = Utf16Parser::new(LONG_MULTILINE_INPUT).parse_bytes().to_vec();
-    let ranges3 = Utf16Parser::new(LONG_MULTILINE_INPUT).parse_v128_portable().to_vec();
+    let ranges3 = Utf16Parser::new(LONG_MULTILINE_INPUT).parse_v128().to_vec();
+    let ranges4 = Utf16Parser::new(LONG_MULTILINE_INPUT).parse_v256().to_vec();
+    let ranges5 = Utf16Parser::new(LONG_MULTILINE_INPUT).parse_v128_portable().to_vec();
 
     assert_eq!(ranges1.len(), ranges2.len());
     assert_eq!(ranges2.len(), ranges3.len());
+    assert_eq!(ranges3.len(), ranges4.len());
+    assert_eq!(ranges4.len(), ranges5.len());
 
     for i in 0..ranges1.len() {
       assert_eq!(ranges1[i], ranges2[i]);
       assert_eq!(ranges2[i], ranges3[i]);
+      assert_eq!(ranges3[i], ranges4[i]);
+      assert_eq!(ranges4[i], ranges5[i]);
     }
   }
 
+  #[test]
+  pub fn parse_line_endings_test() {
+    assert_line_endings::<Utf16Parser>();
+  }
+
+  #[test]
+  pub fn parse_crlf_block_boundary_test() {
+    assert_crlf_block_boundary::<Utf16Parser>();
+  }
+
+  #[test]
+  pub fn parse_sequence_block_boundary_test() {
+    assert_sequence_block_boundary::<Utf16Parser>();
+  }
+
+  #[test]
+  pub fn parse_escape_block_boundary_test() {
+    assert_escape_block_boundary::<Utf16Parser>();
+  }
+
+  #[test]
+  pub fn parse_code_block_boundary_test() {
+    assert_code_block_boundary::<Utf16Parser>();
+  }
+
   #[bench]
   pub fn parse_chars_bench(b: &mut Bencher) {
     b.iter(|| Utf16Parser::new(BENCHMARK_INPUT).parse_chars().len());
@@ -262,8 +438,58 @@ pub mod tests {
     b.iter(|| Utf16Parser::new(BENCHMARK_INPUT).parse_bytes().len());
   }
 
+  #[bench]
+  pub fn parse_v128_bench(b: &mut Bencher) {
+    b.iter(|| Utf16Parser::new(BENCHMARK_INPUT).parse_v128().len());
+  }
+
+  #[bench]
+  pub fn parse_v256_bench(b: &mut Bencher) {
+    b.iter(|| Utf16Parser::new(BENCHMARK_INPUT).parse_v256().len());
+  }
+
   #[bench]
   pub fn parse_v128_portable_bench(b: &mut Bencher) {
     b.iter(|| Utf16Parser::new(BENCHMARK_INPUT).parse_v128_portable().len());
   }
+
+  #[bench]
+  pub fn parse_bitmask_bench(b: &mut Bencher) {
+    b.iter(|| Utf16Parser::new(BENCHMARK_INPUT).parse_bitmask().len());
+  }
+
+  #[bench]
+  pub fn parse_dense_bytes_bench(b: &mut Bencher) {
+    b.iter(|| Utf16Parser::new(DENSE_BRACKETS_INPUT).parse_bytes().len());
+  }
+
+  #[bench]
+  pub fn parse_dense_v128_bench(b: &mut Bencher) {
+    b.iter(|| Utf16Parser::new(DENSE_BRACKETS_INPUT).parse_v128().len());
+  }
+
+  #[bench]
+  pub fn parse_dense_v256_bench(b: &mut Bencher) {
+    b.iter(|| Utf16Parser::new(DENSE_BRACKETS_INPUT).parse_v256().len());
+  }
+
+  #[bench]
+  pub fn parse_dense_v128_portable_bench(b: &mut Bencher) {
+    b.iter(|| Utf16Parser::new(DENSE_BRACKETS_INPUT).parse_v128_portable().len());
+  }
+
+  #[bench]
+  pub fn parse_dense_bitmask_bench(b: &mut Bencher) {
+    b.iter(|| Utf16Parser::new(DENSE_BRACKETS_INPUT).parse_bitmask().len());
+  }
+
+  #[bench]
+  pub fn parse_structural_bench(b: &mut Bencher) {
+    b.iter(|| Utf16Parser::new(BENCHMARK_INPUT).parse_structural().len());
+  }
+
+  #[bench]
+  pub fn parse_dense_structural_bench(b: &mut Bencher) {
+    b.iter(|| Utf16Parser::new(DENSE_BRACKETS_INPUT).parse_structural().len());
+  }
 }
@@ -1,113 +1,83 @@
 //! Parsers that produce character-based offsets.
 
 use std::arch::x86_64::*;
-use std::simd::{u8x16, Simd, SimdPartialEq, SimdUint};
+use std::simd::u8x16;
 
+use crate::brackets::*;
+use crate::lookup::*;
+use crate::parser::*;
+use crate::structural::*;
 use crate::types::*;
 use crate::utf8::*;
 
 pub struct Utf32Parser<'a> {
   input: &'a str,
   position: Position,
-  range_start: Option<Position>,
-  ranges: Vec<Range>,
+  brackets: Brackets,
+  lookup: Lookup,
 }
 
-impl<'a> Utf32Parser<'a> {
-  #[must_use]
-  pub fn new(input: &'a str) -> Self {
-    Self { input, position: Position::default(), range_start: None, ranges: vec![] }
-  }
-
-  pub fn parse_chars(&mut self) -> &[Range] {
-    for char in self.input.chars() {
-      let previous_position = self.position;
+impl<'a> RangeParser<'a> for Utf32Parser<'a> {
+  const ENCODING: Encoding = Encoding::Utf32;
 
-      self.position.character += 1;
-      self.position.offset += char.len_utf8();
-
-      match (char, self.range_start) {
-        (']', Some(start)) => {
-          self.ranges.push(Range { start, end: self.position });
-          self.range_start = None;
-        }
-        ('[', None) => {
-          self.range_start = Some(previous_position);
-        }
-        ('\n', _) => {
-          self.position.line += 1;
-          self.position.character = 0;
-        }
-        _ => {}
-      }
-    }
-    &self.ranges
+  fn with_options(input: &'a str, options: ParseOptions) -> Self {
+    let brackets = Brackets::new(options);
+    let lookup = Lookup::new(brackets.leading_bytes().chain(*b"\n\r"));
+    Self { input, position: Position::default(), brackets, lookup }
   }
 
-  pub fn parse_bytes(&mut self) -> &[Range] {
-    self.parse_bytes_limited(self.input.len());
-    &self.ranges
+  fn brackets(&self) -> &Brackets {
+    &self.brackets
   }
 
-  pub fn parse_bytes_limited(&mut self, limit: usize) {
-    let bytes = self.input.as_bytes();
-
-    let max_offset = (self.position.offset + limit).min(bytes.len());
-
-    while self.position.offset < max_offset {
-      let &byte = unsafe { bytes.get_unchecked(self.position.offset) };
+  fn parse_chars(&mut self) -> &[Range] {
+    let mut previous_char = None;
+    // Characters that belong to a delimiter sequence that was already handled.
+    let mut skip = 0;
 
+    for char in self.input.chars() {
       let previous_position = self.position;
 
-      if is_continuation_byte(byte) {
-        self.position.offset += 1;
-        continue;
-      }
-
-      let character_width = get_character_width(byte);
-
-      self.position.offset += character_width;
       self.position.character += 1;
+      self.position.offset += char.len_utf8();
 
-      match (byte, self.range_start) {
-        (b'\n', _) => {
+      match char {
+        '\r' => {
           self.position.line += 1;
           self.position.character = 0;
         }
-        (b'[', None) => {
-          self.range_start = Some(previous_position);
+        '\n' => {
+          if previous_char != Some('\r') {
+            self.position.line += 1;
+          }
+          self.position.character = 0;
         }
-        (b']', Some(start)) => {
-          self.ranges.push(Range { start, end: self.position });
-          self.range_start = None;
+        _ if skip > 0 => skip -= 1,
+        _ if char.is_ascii() => {
+          skip = self.brackets.handle(self.input.as_bytes(), previous_position).saturating_sub(1);
         }
         _ => {}
       }
+
+      previous_char = Some(char);
     }
+    self.brackets.finish();
+    self.brackets.ranges()
   }
 
-  pub fn parse_v128(&mut self) -> &[Range] {
+  fn parse_bytes(&mut self) -> &[Range] {
+    self.parse_bytes_limited(self.input.len());
+    self.brackets.finish();
+    self.brackets.ranges()
+  }
+
+  fn parse_v128(&mut self) -> &[Range] {
     let bytes = self.input.as_bytes();
 
     while self.position.offset + 15 < bytes.len() {
       let bytes_vec = unsafe { _mm_loadu_si128((bytes[self.position.offset..].as_ptr()).cast()) };
 
-      let lookup: Simd<u8, 16> = if self.range_start.is_some() {
-        // Lookup: ']', '\n'
-        let eq_93 = unsafe { _mm_cmpeq_epi8(bytes_vec, _mm_set1_epi8(b']' as i8)) };
-        let eq_10 = unsafe { _mm_cmpeq_epi8(bytes_vec, _mm_set1_epi8(b'\n' as i8)) };
-
-        unsafe { _mm_or_si128(eq_93, eq_10) }
-      } else {
-        // Lookup: '[', '\n'
-        let eq_91 = unsafe { _mm_cmpeq_epi8(bytes_vec, _mm_set1_epi8(b'[' as i8)) };
-        let eq_10 = unsafe { _mm_cmpeq_epi8(bytes_vec, _mm_set1_epi8(b'\n' as i8)) };
-
-        unsafe { _mm_or_si128(eq_91, eq_10) }
-      }
-      .into();
-
-      if lookup.reduce_or() != 0 {
+      if self.lookup.hit_v128(bytes_vec) {
         self.parse_bytes_limited(16);
       } else {
         self.position.character += count_utf8_characters_v128(bytes_vec);
@@ -117,31 +87,16 @@ impl<'a> Utf32Parser<'a> {
 
     self.parse_bytes();
 
-    &self.ranges
+    self.brackets.ranges()
   }
 
-  pub fn parse_v256(&mut self) -> &[Range] {
+  fn parse_v256(&mut self) -> &[Range] {
     let bytes = self.input.as_bytes();
 
     while self.position.offset + 31 < bytes.len() {
       let bytes_vec = unsafe { _mm256_loadu_si256((bytes[self.position.offset..].as_ptr()).cast()) };
 
-      let lookup: Simd<u8, 32> = if self.range_start.is_some() {
-        // Lookup: ']', '\n'
-        let eq_93 = unsafe { _mm256_cmpeq_epi8(bytes_vec, _mm256_set1_epi8(b']' as i8)) };
-        let eq_10 = unsafe { _mm256_cmpeq_epi8(bytes_vec, _mm256_set1_epi8(b'\n' as i8)) };
-
-        unsafe { _mm256_or_si256(eq_93, eq_10) }
-      } else {
-        // Lookup: '[', '\n'
-        let eq_91 = unsafe { _mm256_cmpeq_epi8(bytes_vec, _mm256_set1_epi8(b'[' as i8)) };
-        let eq_10 = unsafe { _mm256_cmpeq_epi8(bytes_vec, _mm256_set1_epi8(b'\n' as i8)) };
-
-        unsafe { _mm256_or_si256(eq_91, eq_10) }
-      }
-      .into();
-
-      if lookup.reduce_or() != 0 {
+      if self.lookup.hit_v256(bytes_vec) {
         self.parse_bytes_limited(32);
       } else {
         self.position.character += count_utf8_characters_v256(bytes_vec);
@@ -151,31 +106,16 @@ impl<'a> Utf32Parser<'a> {
 
     self.parse_bytes();
 
-    &self.ranges
+    self.brackets.ranges()
   }
 
-  pub fn parse_v128_portable(&mut self) -> &[Range] {
+  fn parse_v128_portable(&mut self) -> &[Range] {
     let bytes = self.input.as_bytes();
 
     while self.position.offset + 15 < bytes.len() {
       let bytes_vec = u8x16::from_slice(&bytes[self.position.offset..]);
 
-      let lookup = if self.range_start.is_some() {
-        // Lookup: ']', '\n'
-        let eq_93 = bytes_vec.simd_eq(u8x16::splat(b']'));
-        let eq_10 = bytes_vec.simd_eq(u8x16::splat(b'\n'));
-
-        eq_93 | eq_10
-      } else {
-        // Lookup: '[', '\n'
-
-        let eq_91 = bytes_vec.simd_eq(u8x16::splat(b'['));
-        let eq_10 = bytes_vec.simd_eq(u8x16::splat(b'\n'));
-
-        eq_91 | eq_10
-      };
-
-      if lookup.any() {
+      if self.lookup.hit_v128_portable(bytes_vec) {
         self.parse_bytes_limited(16);
       } else {
         self.position.character += count_utf8_characters_v128_portable(bytes_vec);
@@ -185,7 +125,97 @@ impl<'a> Utf32Parser<'a> {
 
     self.parse_bytes();
 
-    &self.ranges
+    self.brackets.ranges()
+  }
+
+  fn parse_bitmask(&mut self) -> &[Range] {
+    let bytes = self.input.as_bytes();
+
+    while self.position.offset + 31 < bytes.len() {
+      let block = self.position.offset;
+      let bytes_vec = unsafe { _mm256_loadu_si256((bytes[block..].as_ptr()).cast()) };
+      let starts = character_starts_v256(bytes_vec);
+
+      let mut hits = self.lookup.mask_v256(bytes_vec);
+      // Bytes of the block before `counted` are already counted.
+      let mut counted = 0;
+      while hits != 0 {
+        let hit = hits.trailing_zeros() as usize;
+        self.position.character += (starts & bits_between(counted, hit)).count_ones() as usize;
+        self.position.offset = block + hit;
+        self.parse_bytes_limited(1);
+        // A delimiter sequence can take up more than one hit and even cross the block boundary.
+        counted = (self.position.offset - block).min(32);
+        hits &= !bits_between(0, counted);
+      }
+      self.position.character += (starts & bits_between(counted, 32)).count_ones() as usize;
+      self.position.offset = self.position.offset.max(block + 32);
+    }
+
+    self.parse_bytes();
+
+    self.brackets.ranges()
+  }
+
+  fn parse_structural(&mut self) -> &[Range] {
+    let index = StructuralIndex::new(self.input, &self.lookup);
+
+    for &offset in index.offsets() {
+      let offset = offset as usize;
+      // Offsets before the current one belong to an already handled delimiter sequence.
+      if offset >= self.position.offset {
+        self.position.character += index.characters(offset) - index.characters(self.position.offset);
+        self.position.offset = offset;
+        self.parse_bytes_limited(1);
+      }
+    }
+
+    self.brackets.finish();
+    self.brackets.ranges()
+  }
+}
+
+impl<'a> Utf32Parser<'a> {
+  pub fn parse_bytes_limited(&mut self, limit: usize) {
+    let bytes = self.input.as_bytes();
+
+    let max_offset = (self.position.offset + limit).min(bytes.len());
+
+    while self.position.offset < max_offset {
+      let &byte = unsafe { bytes.get_unchecked(self.position.offset) };
+
+      let previous_position = self.position;
+
+      if is_continuation_byte(byte) {
+        self.position.offset += 1;
+        continue;
+      }
+
+      let character_width = get_character_width(byte);
+
+      self.position.offset += character_width;
+      self.position.character += 1;
+
+      match byte {
+        b'\r' => {
+          self.position.line += 1;
+          self.position.character = 0;
+        }
+        b'\n' => {
+          if !is_crlf_line_feed(bytes, previous_position.offset) {
+            self.position.line += 1;
+          }
+          self.position.character = 0;
+        }
+        _ if self.brackets.is_delimiter(byte) => {
+          // Delimiters are ASCII, so the rest of the sequence is one column per byte.
+          let skipped = self.brackets.handle(bytes, previous_position).saturating_sub(1);
+          self.position.offset += skipped;
+          self.position.character += skipped;
+        }
+        _ => {}
+      }
+    }
   }
 }
 
@@ -197,28 +227,29 @@ pub mod tests {
   use proptest::prelude::*;
 
   use super::*;
+  use crate::parser::tests::*;
   use crate::tests::test_data::*;
 
   proptest! {
     #[test]
-    fn parse_property_test(s in "[0-9a-zA-Zа-яА-Я\\[\\]\\n]{300}") {
-      let ranges1 = Utf32Parser::new(s.as_str()).parse_chars().to_vec();
-      let ranges2 = Utf32Parser::new(s.as_str()).parse_bytes().to_vec();
-      let ranges3 = Utf32Parser::new(s.as_str()).parse_v128().to_vec();
-      let ranges4 = Utf32Parser::new(s.as_str()).parse_v256().to_vec();
-      let ranges5 = Utf32Parser::new(s.as_str()).parse_v128_portable().to_vec();
-
-      assert_eq!(ranges1.len(), ranges2.len());
-      assert_eq!(ranges2.len(), ranges3.len());
-      assert_eq!(ranges3.len(), ranges4.len());
-      assert_eq!(ranges4.len(), ranges5.len());
-
-      for i in 0..ranges1.len() {
-        assert_eq!(ranges1[i], ranges2[i], "ranges1[i] == ranges2[i]");
-        assert_eq!(ranges2[i], ranges3[i], "ranges2[i] == ranges3[i]");
-        assert_eq!(ranges3[i], ranges4[i], "ranges3[i] == ranges4[i]");
-        assert_eq!(ranges3[i], ranges5[i], "ranges4[i] == ranges5[i]");
-      }
+    fn parse_property_test(s in "[0-9a-zA-Zа-яА-Я\\[\\]\\n\\r]{300}") {
+      assert_strategies_agree::<Utf32Parser>(&s);
+    }
+
+    #[test]
+    fn parse_options_property_test(
+      s in "[0-9a-zA-Zа-яА-Я \\[\\]()$`~\\\\\\n\\r]{300}",
+      max_depth in 1..8usize,
+      escapes in any::<bool>(),
+      code in any::<bool>(),
+      single_line in any::<bool>(),
+    ) {
+      assert_options_property::<Utf32Parser>(&s, max_depth, escapes, code, single_line);
+    }
+
+    #[test]
+    fn parse_sequences_property_test(s in "[0-9a-zA-Zа-яА-Я\\[\\]()$`\\\\\\n\\r]{300}", max_depth in 1..8usize) {
+      assert_sequences_property::<Utf32Parser>(&s, max_depth);
     }
   }
 
@@ -365,6 +396,31 @@ pub mod tests {
     }
   }
 
+  #[test]
+  pub fn parse_line_endings_test() {
+    assert_line_endings::<Utf32Parser>();
+  }
+
+  #[test]
+  pub fn parse_crlf_block_boundary_test() {
+    assert_crlf_block_boundary::<Utf32Parser>();
+  }
+
+  #[test]
+  pub fn parse_sequence_block_boundary_test() {
+    assert_sequence_block_boundary::<Utf32Parser>();
+  }
+
+  #[test]
+  pub fn parse_escape_block_boundary_test() {
+    assert_escape_block_boundary::<Utf32Parser>();
+  }
+
+  #[test]
+  pub fn parse_code_block_boundary_test() {
+    assert_code_block_boundary::<Utf32Parser>();
+  }
+
   #[bench]
   pub fn parse_chars_bench(b: &mut Bencher) {
     b.iter(|| Utf32Parser::new(BENCHMARK_INPUT).parse_chars().len());
@@ -389,4 +445,44 @@ pub mod tests {
   pub fn parse_v128_portable_bench(b: &mut Bencher) {
     b.iter(|| Utf32Parser::new(BENCHMARK_INPUT).parse_v128_portable().len());
   }
+
+  #[bench]
+  pub fn parse_bitmask_bench(b: &mut Bencher) {
+    b.iter(|| Utf32Parser::new(BENCHMARK_INPUT).parse_bitmask().len());
+  }
+
+  #[bench]
+  pub fn parse_dense_bytes_bench(b: &mut Bencher) {
+    b.iter(|| Utf32Parser::new(DENSE_BRACKETS_INPUT).parse_bytes().len());
+  }
+
+  #[bench]
+  pub fn parse_dense_v128_bench(b: &mut Bencher) {
+    b.iter(|| Utf32Parser::new(DENSE_BRACKETS_INPUT).parse_v128().len());
+  }
+
+  #[bench]
+  pub fn parse_dense_v256_bench(b: &mut Bencher) {
+    b.iter(|| Utf32Parser::new(DENSE_BRACKETS_INPUT).parse_v256().len());
+  }
+
+  #[bench]
+  pub fn parse_dense_v128_portable_bench(b: &mut Bencher) {
+    b.iter(|| Utf32Parser::new(DENSE_BRACKETS_INPUT).parse_v128_portable().len());
+  }
+
+  #[bench]
+  pub fn parse_dense_bitmask_bench(b: &mut Bencher) {
+    b.iter(|| Utf32Parser::new(DENSE_BRACKETS_INPUT).parse_bitmask().len());
+  }
+
+  #[bench]
+  pub fn parse_structural_bench(b: &mut Bencher) {
+    b.iter(|| Utf32Parser::new(BENCHMARK_INPUT).parse_structural().len());
+  }
+
+  #[bench]
+  pub fn parse_dense_structural_bench(b: &mut Bencher) {
+    b.iter(|| Utf32Parser::new(DENSE_BRACKETS_INPUT).parse_structural().len());
+  }
 }
@@ -1,5 +1,5 @@
 use std::arch::x86_64::*;
-use std::simd::{u8x16, Simd, SimdPartialEq};
+use std::simd::{u8x16, Simd, SimdPartialEq, SimdPartialOrd};
 
 #[cfg(test)]
 #[derive(Copy, Clone)]
@@ -44,6 +44,109 @@ fn count_utf8_characters(bytes: &[u8], vectorization: Option<Vectorization>) ->
   count
 }
 
+#[cfg(test)]
+fn count_utf16_units_vectorized(bytes: &[u8], vectorization: Option<Vectorization>) -> usize {
+  let mut i = 0;
+  let mut count = 0;
+  match vectorization {
+    Some(Vectorization::Intel128) => {
+      while i + 15 < bytes.len() {
+        let v = unsafe { _mm_loadu_si128((bytes[i..].as_ptr()).cast()) };
+        count += count_utf16_units_v128(v);
+        i += 16;
+      }
+    }
+    Some(Vectorization::Intel256) => {
+      while i + 31 < bytes.len() {
+        let v = unsafe { _mm256_loadu_si256((bytes[i..].as_ptr()).cast()) };
+        count += count_utf16_units_v256(v);
+        i += 32;
+      }
+    }
+    Some(Vectorization::Portable128) => {
+      while i + 15 < bytes.len() {
+        let v = u8x16::from_slice(&bytes[i..]);
+        count += count_utf16_units_v128_portable(v);
+        i += 16;
+      }
+    }
+    None => {}
+  }
+
+  count + count_utf16_units_scalar(&bytes[i..])
+}
+
+/// Counter of the UTF-16 code units that UTF-8 bytes take.
+pub type Utf16Counter = fn(&[u8]) -> usize;
+
+/// Number of UTF-16 code units that the UTF-8 bytes take, counted with [`utf16_units_counter`]. The bytes don't have to
+/// start or end at a character boundary: a character is counted where its leading byte is. Loops that count many
+/// short slices should pick the counter once instead.
+#[must_use]
+pub fn count_utf16_units(bytes: &[u8]) -> usize {
+  utf16_units_counter()(bytes)
+}
+
+/// Picks the fastest counter for the CPU: 32-byte blocks with AVX2 if it has it, and 16-byte blocks with portable SIMD
+/// otherwise (and under Miri).
+#[must_use]
+pub fn utf16_units_counter() -> Utf16Counter {
+  #[cfg(not(miri))]
+  if is_x86_feature_detected!("avx2") {
+    return count_utf16_units_avx2;
+  }
+  count_utf16_units_portable
+}
+
+#[cfg(not(miri))]
+fn count_utf16_units_avx2(bytes: &[u8]) -> usize {
+  // Only picked by `utf16_units_counter` when the CPU has AVX2.
+  unsafe { count_utf16_units_avx2_unchecked(bytes) }
+}
+
+/// The loop is compiled with AVX2 enabled, so that intrinsics are inlined into it.
+#[cfg(not(miri))]
+#[target_feature(enable = "avx2")]
+unsafe fn count_utf16_units_avx2_unchecked(bytes: &[u8]) -> usize {
+  let mut i = 0;
+  let mut count = 0;
+  while i + 31 < bytes.len() {
+    count += count_utf16_units_v256(_mm256_loadu_si256((bytes[i..].as_ptr()).cast()));
+    i += 32;
+  }
+  count + count_utf16_units_portable(&bytes[i..])
+}
+
+fn count_utf16_units_portable(bytes: &[u8]) -> usize {
+  let mut i = 0;
+  let mut count = 0;
+  while i + 15 < bytes.len() {
+    count += count_utf16_units_v128_portable(u8x16::from_slice(&bytes[i..]));
+    i += 16;
+  }
+  count + count_utf16_units_scalar(&bytes[i..])
+}
+
+pub fn count_utf16_units_scalar(bytes: &[u8]) -> usize {
+  bytes.iter().map(|&byte| usize::from(!is_continuation_byte(byte)) + usize::from(byte >= 0b1111_0000)).sum()
+}
+
+/// 4-byte characters take 2 UTF-16 code units, and all other characters take 1.
+#[inline]
+pub fn count_utf16_units_v128(v: __m128i) -> usize {
+  count_utf8_characters_v128(v) + count_four_byte_starts_v128(v)
+}
+
+#[inline]
+pub fn count_utf16_units_v128_portable(v: u8x16) -> usize {
+  count_utf8_characters_v128_portable(v) + count_four_byte_starts_v128_portable(v)
+}
+
+#[inline]
+pub fn count_utf16_units_v256(v: __m256i) -> usize {
+  (character_starts_v256(v).count_ones() + four_byte_starts_v256(v).count_ones()) as usize
+}
+
 pub fn count_utf8_characters_scalar(bytes: &[u8]) -> usize {
   bytes.iter().filter(|&&byte| !is_continuation_byte(byte)).count()
 }
@@ -75,11 +178,57 @@ pub fn count_utf8_characters_v256(v: __m256i) -> usize {
   32 - continuation_bytes
 }
 
+/// Number of bytes of the 16-byte block that start 4-byte characters (the ones that take 2 UTF-16 code units).
+#[inline]
+pub fn count_four_byte_starts_v128(v: __m128i) -> usize {
+  unsafe { (_mm_movemask_epi8(_mm_cmpgt_epi8(v, _mm_set1_epi8(-17))) & _mm_movemask_epi8(v)).count_ones() as usize }
+}
+
+#[inline]
+pub fn count_four_byte_starts_v128_portable(v: u8x16) -> usize {
+  v.simd_ge(u8x16::splat(0b1111_0000)).to_array().iter().filter(|&&c| c).count()
+}
+
+/// Bitmask of the bytes of the 32-byte block that start a character, i.e. aren't continuation bytes.
+#[inline]
+pub fn character_starts_v256(v: __m256i) -> u32 {
+  unsafe { _mm256_movemask_epi8(_mm256_cmpgt_epi8(v, _mm256_set1_epi8(-65))) as u32 }
+}
+
+/// Bitmask of the bytes of the 32-byte block that start 4-byte characters (the ones that take 2 UTF-16 code units).
+#[inline]
+pub fn four_byte_starts_v256(v: __m256i) -> u32 {
+  unsafe { (_mm256_movemask_epi8(_mm256_cmpgt_epi8(v, _mm256_set1_epi8(-17))) & _mm256_movemask_epi8(v)) as u32 }
+}
+
+/// Bitmask with bits from `start` (inclusive) to `end` (exclusive) set, for bitmasks of 32-byte blocks.
+#[inline]
+pub const fn bits_between(start: usize, end: usize) -> u32 {
+  (((1u64 << end) - 1) & !((1u64 << start) - 1)) as u32
+}
+
 #[inline]
 pub const fn is_continuation_byte(byte: u8) -> bool {
   (byte as i8) < -64
 }
 
+/// Checks whether the line feed at `offset` is the second half of a `\r\n` line ending, in which case the line has
+/// already been ended by the carriage return.
+#[inline]
+pub fn is_crlf_line_feed(bytes: &[u8], offset: usize) -> bool {
+  offset > 0 && bytes[offset - 1] == b'\r'
+}
+
+/// Checks whether the byte at `offset` is escaped, i.e. preceded by an odd number of backslashes.
+///
+/// Only the backslashes right before the byte are visited, and they can't precede any other byte, so checking every
+/// delimiter of the input takes linear time overall.
+#[inline]
+pub fn is_escaped(bytes: &[u8], offset: usize) -> bool {
+  let backslashes = bytes[..offset].iter().rev().take_while(|&&byte| byte == b'\\').count();
+  backslashes % 2 == 1
+}
+
 #[inline]
 pub const fn get_character_width(starting_byte: u8) -> usize {
   if starting_byte < 0b1100_0000 {
@@ -161,6 +310,43 @@ mod tests {
     }
   }
 
+  #[test]
+  pub fn count_utf16_units_test() {
+    let emoji = format!("{}😀{}", "ы".repeat(30), "🎲a".repeat(20));
+    for input in [SHORT_ASCII_INPUT, SHORT_UNICODE_INPUT, LONG_MULTILINE_INPUT, DENSE_BRACKETS_INPUT, &emoji] {
+      let expected = input.encode_utf16().count();
+      let bytes = input.as_bytes();
+
+      assert_eq!(count_utf16_units(bytes), expected);
+      assert_eq!(count_utf16_units_portable(bytes), expected);
+      assert_eq!(count_utf16_units_scalar(bytes), expected);
+      for vectorization in [Vectorization::Intel128, Vectorization::Intel256, Vectorization::Portable128] {
+        assert_eq!(count_utf16_units_vectorized(bytes, Some(vectorization)), expected);
+      }
+
+      // Splitting the bytes at any offset (even inside of a character) doesn't change the total.
+      for i in [1, 17, 33, bytes.len() / 2].into_iter().filter(|&i| i <= bytes.len()) {
+        assert_eq!(count_utf16_units(&bytes[..i]) + count_utf16_units(&bytes[i..]), expected);
+      }
+    }
+  }
+
+  #[test]
+  pub fn count_four_byte_starts_test() {
+    let input = "😀aы😀ёabc🎲_😀-b😀c";
+    let bytes = input.as_bytes();
+    assert_eq!(bytes.len(), 32);
+
+    let v128 = unsafe { _mm_loadu_si128(bytes.as_ptr().cast()) };
+    let v256 = unsafe { _mm256_loadu_si256(bytes.as_ptr().cast()) };
+    let expected = input[..16].encode_utf16().count() - input[..16].chars().count();
+
+    assert_eq!(count_four_byte_starts_v128(v128), expected);
+    assert_eq!(count_four_byte_starts_v128_portable(u8x16::from_slice(bytes)), expected);
+    assert_eq!(four_byte_starts_v256(v256).count_ones(), 5);
+    assert_eq!(character_starts_v256(v256).count_ones() as usize, input.chars().count());
+  }
+
   #[bench]
   pub fn count_characters_vector128_bench(b: &mut Bencher) {
     b.iter(|| count_utf8_characters(BENCHMARK_INPUT.as_bytes(), Some(Vectorization::Intel128)));
@@ -187,8 +373,52 @@ mod tests {
   }
 
   #[bench]
-  #[cfg(not(miri))]
+  #[cfg(all(feature = "simdutf", not(miri)))]
   pub fn count_characters_simdutf(b: &mut Bencher) {
     b.iter(|| unsafe { simdutf::count_utf32_from_utf8(BENCHMARK_INPUT.as_bytes()) });
   }
+
+  #[bench]
+  pub fn count_utf16_units_bench(b: &mut Bencher) {
+    b.iter(|| count_utf16_units(BENCHMARK_INPUT.as_bytes()));
+  }
+
+  /// Counts between lines, as the parsers do between brackets, detecting CPU features on every call.
+  #[bench]
+  pub fn count_utf16_units_lines_bench(b: &mut Bencher) {
+    b.iter(|| BENCHMARK_INPUT.lines().map(|line| count_utf16_units(line.as_bytes())).sum::<usize>());
+  }
+
+  /// Counts between lines with a counter that is picked once.
+  #[bench]
+  pub fn utf16_units_counter_lines_bench(b: &mut Bencher) {
+    let counter = utf16_units_counter();
+    b.iter(|| BENCHMARK_INPUT.lines().map(|line| counter(line.as_bytes())).sum::<usize>());
+  }
+
+  #[bench]
+  pub fn count_utf16_units_vector128_bench(b: &mut Bencher) {
+    b.iter(|| count_utf16_units_vectorized(BENCHMARK_INPUT.as_bytes(), Some(Vectorization::Intel128)));
+  }
+
+  #[bench]
+  pub fn count_utf16_units_vector256_bench(b: &mut Bencher) {
+    b.iter(|| count_utf16_units_vectorized(BENCHMARK_INPUT.as_bytes(), Some(Vectorization::Intel256)));
+  }
+
+  #[bench]
+  pub fn count_utf16_units_vector128_portable_bench(b: &mut Bencher) {
+    b.iter(|| count_utf16_units_vectorized(BENCHMARK_INPUT.as_bytes(), Some(Vectorization::Portable128)));
+  }
+
+  #[bench]
+  pub fn count_utf16_units_bytes_bench(b: &mut Bencher) {
+    b.iter(|| count_utf16_units_scalar(BENCHMARK_INPUT.as_bytes()));
+  }
+
+  #[bench]
+  #[cfg(all(feature = "simdutf", not(miri)))]
+  pub fn count_utf16_units_simdutf_bench(b: &mut Bencher) {
+    b.iter(|| unsafe { simdutf::count_utf16_from_utf8(BENCHMARK_INPUT.as_bytes()) });
+  }
 }
@@ -0,0 +1,451 @@
+//! Parsers that produce offsets based on UTF-8 code units (byte columns, LSP `positionEncoding: "utf-8"`).
+
+use std::arch::x86_64::*;
+use std::simd::u8x16;
+
+use crate::brackets::*;
+use crate::lookup::*;
+use crate::parser::*;
+use crate::structural::*;
+use crate::types::*;
+use crate::utf8::*;
+
+pub struct Utf8Parser<'a> {
+  input: &'a str,
+  offset: usize,
+  line: usize,
+  line_offset: usize,
+  brackets: Brackets,
+  lookup: Lookup,
+}
+
+impl<'a> RangeParser<'a> for Utf8Parser<'a> {
+  const ENCODING: Encoding = Encoding::Utf8;
+
+  fn with_options(input: &'a str, options: ParseOptions) -> Self {
+    let brackets = Brackets::new(options);
+    let lookup = Lookup::new(brackets.leading_bytes().chain(*b"\n\r"));
+    Self { input, offset: 0, line: 0, line_offset: 0, brackets, lookup }
+  }
+
+  fn brackets(&self) -> &Brackets {
+    &self.brackets
+  }
+
+  fn parse_chars(&mut self) -> &[Range] {
+    let mut line = 0;
+    let mut character = 0;
+    let mut previous_char = None;
+    // Characters that belong to a delimiter sequence that was already handled.
+    let mut skip = 0;
+
+    for char in self.input.chars() {
+      let previous_position = Position { line, character, offset: self.offset };
+
+      character += char.len_utf8();
+      self.offset += char.len_utf8();
+
+      match char {
+        '\r' => {
+          line += 1;
+          character = 0;
+        }
+        '\n' => {
+          if previous_char != Some('\r') {
+            line += 1;
+          }
+          character = 0;
+        }
+        _ if skip > 0 => skip -= 1,
+        _ if char.is_ascii() => {
+          skip = self.brackets.handle(self.input.as_bytes(), previous_position).saturating_sub(1);
+        }
+        _ => {}
+      }
+
+      previous_char = Some(char);
+    }
+    self.brackets.finish();
+    self.brackets.ranges()
+  }
+
+  fn parse_bytes(&mut self) -> &[Range] {
+    self.parse_bytes_limited(self.input.len());
+    self.brackets.finish();
+    self.brackets.ranges()
+  }
+
+  fn parse_v128(&mut self) -> &[Range] {
+    let bytes = self.input.as_bytes();
+
+    while self.offset + 15 < bytes.len() {
+      let bytes_vec = unsafe { _mm_loadu_si128((bytes[self.offset..].as_ptr()).cast()) };
+
+      if self.lookup.hit_v128(bytes_vec) {
+        self.parse_bytes_limited(16);
+      } else {
+        self.offset += 16;
+      }
+    }
+
+    self.parse_bytes();
+
+    self.brackets.ranges()
+  }
+
+  fn parse_v256(&mut self) -> &[Range] {
+    let bytes = self.input.as_bytes();
+
+    while self.offset + 31 < bytes.len() {
+      let bytes_vec = unsafe { _mm256_loadu_si256((bytes[self.offset..].as_ptr()).cast()) };
+
+      if self.lookup.hit_v256(bytes_vec) {
+        self.parse_bytes_limited(32);
+      } else {
+        self.offset += 32;
+      }
+    }
+
+    self.parse_bytes();
+
+    self.brackets.ranges()
+  }
+
+  fn parse_v128_portable(&mut self) -> &[Range] {
+    let bytes = self.input.as_bytes();
+
+    while self.offset + 15 < bytes.len() {
+      let bytes_vec = u8x16::from_slice(&bytes[self.offset..]);
+
+      if self.lookup.hit_v128_portable(bytes_vec) {
+        self.parse_bytes_limited(16);
+      } else {
+        self.offset += 16;
+      }
+    }
+
+    self.parse_bytes();
+
+    self.brackets.ranges()
+  }
+
+  fn parse_bitmask(&mut self) -> &[Range] {
+    let bytes = self.input.as_bytes();
+
+    while self.offset + 31 < bytes.len() {
+      let block = self.offset;
+      let bytes_vec = unsafe { _mm256_loadu_si256((bytes[block..].as_ptr()).cast()) };
+
+      let mut hits = self.lookup.mask_v256(bytes_vec);
+      while hits != 0 {
+        self.offset = block + hits.trailing_zeros() as usize;
+        self.parse_bytes_limited(1);
+        // A delimiter sequence can take up more than one hit.
+        hits &= !bits_between(0, (self.offset - block).min(32));
+      }
+      self.offset = self.offset.max(block + 32);
+    }
+
+    self.parse_bytes();
+
+    self.brackets.ranges()
+  }
+
+  fn parse_structural(&mut self) -> &[Range] {
+    let index = StructuralIndex::new(self.input, &self.lookup);
+
+    for &offset in index.offsets() {
+      let offset = offset as usize;
+      // Offsets before the current one belong to an already handled delimiter sequence.
+      if offset >= self.offset {
+        self.offset = offset;
+        self.parse_bytes_limited(1);
+      }
+    }
+
+    self.brackets.finish();
+    self.brackets.ranges()
+  }
+}
+
+impl<'a> Utf8Parser<'a> {
+  pub fn parse_bytes_limited(&mut self, limit: usize) {
+    let bytes = self.input.as_bytes();
+
+    let max_offset = (self.offset + limit).min(bytes.len());
+
+    // Delimiters and line endings are never a part of a multi-byte character, so there's no need to find character
+    // boundaries: every byte is a column.
+    while self.offset < max_offset {
+      let &byte = unsafe { bytes.get_unchecked(self.offset) };
+
+      self.offset += 1;
+
+      match byte {
+        b'\r' => {
+          self.line += 1;
+          self.line_offset = self.offset;
+        }
+        b'\n' => {
+          if !is_crlf_line_feed(bytes, self.offset - 1) {
+            self.line += 1;
+          }
+          self.line_offset = self.offset;
+        }
+        _ if self.brackets.is_delimiter(byte) => {
+          let offset = self.offset - 1;
+          let position = Position { line: self.line, character: offset - self.line_offset, offset };
+          self.offset += self.brackets.handle(bytes, position).saturating_sub(1);
+        }
+        _ => {}
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+pub mod tests {
+  extern crate test;
+  use test::bench::Bencher;
+
+  use proptest::prelude::*;
+
+  use super::*;
+  use crate::parser::tests::*;
+  use crate::tests::test_data::*;
+
+  proptest! {
+    #[test]
+    fn parse_property_test(s in "[0-9a-zA-Zа-яА-Я\\[\\]\\n\\r]{300}") {
+      assert_strategies_agree::<Utf8Parser>(&s);
+    }
+
+    #[test]
+    fn parse_options_property_test(
+      s in "[0-9a-zA-Zа-яА-Я \\[\\]()$`~\\\\\\n\\r]{300}",
+      max_depth in 1..8usize,
+      escapes in any::<bool>(),
+      code in any::<bool>(),
+      single_line in any::<bool>(),
+    ) {
+      assert_options_property::<Utf8Parser>(&s, max_depth, escapes, code, single_line);
+    }
+
+    #[test]
+    fn parse_sequences_property_test(s in "[0-9a-zA-Zа-яА-Я\\[\\]()$`\\\\\\n\\r]{300}", max_depth in 1..8usize) {
+      assert_sequences_property::<Utf8Parser>(&s, max_depth);
+    }
+  }
+
+  #[test]
+  pub fn parse_small_ascii_test() {
+    let ranges1 = Utf8Parser::new(SHORT_ASCII_INPUT).parse_chars().to_vec();
+    let ranges2 = Utf8Parser::new(SHORT_ASCII_INPUT).parse_bytes().to_vec();
+    let ranges3 = Utf8Parser::new(SHORT_ASCII_INPUT).parse_v128().to_vec();
+    let ranges4 = Utf8Parser::new(SHORT_ASCII_INPUT).parse_v256().to_vec();
+    let ranges5 = Utf8Parser::new(SHORT_ASCII_INPUT).parse_v128_portable().to_vec();
+
+    for ranges in [ranges1, ranges2, ranges3, ranges4, ranges5] {
+      assert_eq!(ranges.len(), 1);
+      assert_eq!(
+        ranges[0],
+        Range {
+          start: Position { line: 0, character: 4, offset: 4 },
+          end: Position { line: 0, character: 9, offset: 9 }
+        }
+      );
+    }
+  }
+
+  #[test]
+  pub fn parse_small_unicode_test() {
+    let ranges1 = Utf8Parser::new(SHORT_UNICODE_INPUT).parse_chars().to_vec();
+    let ranges2 = Utf8Parser::new(SHORT_UNICODE_INPUT).parse_bytes().to_vec();
+    let ranges3 = Utf8Parser::new(SHORT_UNICODE_INPUT).parse_v128().to_vec();
+    let ranges4 = Utf8Parser::new(SHORT_UNICODE_INPUT).parse_v256().to_vec();
+    let ranges5 = Utf8Parser::new(SHORT_UNICODE_INPUT).parse_v128_portable().to_vec();
+
+    for ranges in [ranges1, ranges2, ranges3, ranges4, ranges5] {
+      assert_eq!(ranges.len(), 1);
+      assert_eq!(
+        ranges[0],
+        Range {
+          start: Position { line: 0, character: 7, offset: 7 },
+          end: Position { line: 0, character: 15, offset: 15 }
+        }
+      );
+    }
+  }
+
+  #[test]
+  pub fn medium_ascii_test() {
+    let ranges1 = Utf8Parser::new(LONG_ASCII_INPUT).parse_chars().to_vec();
+    let ranges2 = Utf8Parser::new(LONG_ASCII_INPUT).parse_bytes().to_vec();
+    let ranges3 = Utf8Parser::new(LONG_ASCII_INPUT).parse_v128().to_vec();
+    let ranges4 = Utf8Parser::new(LONG_ASCII_INPUT).parse_v256().to_vec();
+    let ranges5 = Utf8Parser::new(LONG_ASCII_INPUT).parse_v128_portable().to_vec();
+
+    for ranges in [ranges1, ranges2, ranges3, ranges4, ranges5] {
+      assert_eq!(ranges.len(), 1);
+      assert_eq!(
+        ranges[0],
+        Range {
+          start: Position { line: 0, character: 42, offset: 42 },
+          end: Position { line: 0, character: 55, offset: 55 }
+        }
+      );
+    }
+  }
+
+  #[test]
+  pub fn medium_unicode_test() {
+    let ranges1 = Utf8Parser::new(LONG_UNICODE_INPUT).parse_chars().to_vec();
+    let ranges2 = Utf8Parser::new(LONG_UNICODE_INPUT).parse_bytes().to_vec();
+    let ranges3 = Utf8Parser::new(LONG_UNICODE_INPUT).parse_v128().to_vec();
+    let ranges4 = Utf8Parser::new(LONG_UNICODE_INPUT).parse_v256().to_vec();
+    let ranges5 = Utf8Parser::new(LONG_UNICODE_INPUT).parse_v128_portable().to_vec();
+
+    for ranges in [ranges1, ranges2, ranges3, ranges4, ranges5] {
+      assert_eq!(ranges.len(), 1);
+      assert_eq!(
+        ranges[0],
+        Range {
+          start: Position { line: 0, character: 66, offset: 66 },
+          end: Position { line: 0, character: 88, offset: 88 }
+        }
+      );
+    }
+  }
+
+  #[test]
+  pub fn short_multiline_test() {
+    let ranges1 = Utf8Parser::new(SHORT_MULTILINE_INPUT).parse_chars().to_vec();
+    let ranges2 = Utf8Parser::new(SHORT_MULTILINE_INPUT).parse_bytes().to_vec();
+    let ranges3 = Utf8Parser::new(SHORT_MULTILINE_INPUT).parse_v128().to_vec();
+    let ranges4 = Utf8Parser::new(SHORT_MULTILINE_INPUT).parse_v256().to_vec();
+    let ranges5 = Utf8Parser::new(SHORT_MULTILINE_INPUT).parse_v128_portable().to_vec();
+
+    assert_eq!(ranges1.len(), ranges2.len());
+    assert_eq!(ranges2.len(), ranges3.len());
+    assert_eq!(ranges3.len(), ranges4.len());
+    assert_eq!(ranges4.len(), ranges5.len());
+
+    for i in 0..ranges1.len() {
+      assert_eq!(ranges1[i], ranges2[i]);
+      assert_eq!(ranges2[i], ranges3[i]);
+      assert_eq!(ranges3[i], ranges4[i]);
+      assert_eq!(ranges4[i], ranges5[i]);
+    }
+  }
+
+  #[test]
+  pub fn long_multiline_test() {
+    let ranges1 = Utf8Parser::new(LONG_MULTILINE_INPUT).parse_chars().to_vec();
+    let ranges2 = Utf8Parser::new(LONG_MULTILINE_INPUT).parse_bytes().to_vec();
+    let ranges3 = Utf8Parser::new(LONG_MULTILINE_INPUT).parse_v128().to_vec();
+    let ranges4 = Utf8Parser::new(LONG_MULTILINE_INPUT).parse_v256().to_vec();
+    let ranges5 = Utf8Parser::new(LONG_MULTILINE_INPUT).parse_v128_portable().to_vec();
+
+    assert_eq!(ranges1.len(), ranges2.len());
+    assert_eq!(ranges2.len(), ranges3.len());
+    assert_eq!(ranges3.len(), ranges4.len());
+    assert_eq!(ranges4.len(), ranges5.len());
+
+    for i in 0..ranges1.len() {
+      assert_eq!(ranges1[i], ranges2[i]);
+      assert_eq!(ranges2[i], ranges3[i]);
+      assert_eq!(ranges3[i], ranges4[i]);
+      assert_eq!(ranges4[i], ranges5[i]);
+    }
+  }
+
+  #[test]
+  pub fn parse_line_endings_test() {
+    assert_line_endings::<Utf8Parser>();
+  }
+
+  #[test]
+  pub fn parse_crlf_block_boundary_test() {
+    assert_crlf_block_boundary::<Utf8Parser>();
+  }
+
+  #[test]
+  pub fn parse_sequence_block_boundary_test() {
+    assert_sequence_block_boundary::<Utf8Parser>();
+  }
+
+  #[test]
+  pub fn parse_escape_block_boundary_test() {
+    assert_escape_block_boundary::<Utf8Parser>();
+  }
+
+  #[test]
+  pub fn parse_code_block_boundary_test() {
+    assert_code_block_boundary::<Utf8Parser>();
+  }
+
+  #[bench]
+  pub fn parse_chars_bench(b: &mut Bencher) {
+    b.iter(|| Utf8Parser::new(BENCHMARK_INPUT).parse_chars().len());
+  }
+
+  #[bench]
+  pub fn parse_bytes_bench(b: &mut Bencher) {
+    b.iter(|| Utf8Parser::new(BENCHMARK_INPUT).parse_bytes().len());
+  }
+
+  #[bench]
+  pub fn parse_v128_bench(b: &mut Bencher) {
+    b.iter(|| Utf8Parser::new(BENCHMARK_INPUT).parse_v128().len());
+  }
+
+  #[bench]
+  pub fn parse_v256_bench(b: &mut Bencher) {
+    b.iter(|| Utf8Parser::new(BENCHMARK_INPUT).parse_v256().len());
+  }
+
+  #[bench]
+  pub fn parse_v128_portable_bench(b: &mut Bencher) {
+    b.iter(|| Utf8Parser::new(BENCHMARK_INPUT).parse_v128_portable().len());
+  }
+
+  #[bench]
+  pub fn parse_bitmask_bench(b: &mut Bencher) {
+    b.iter(|| Utf8Parser::new(BENCHMARK_INPUT).parse_bitmask().len());
+  }
+
+  #[bench]
+  pub fn parse_dense_bytes_bench(b: &mut Bencher) {
+    b.iter(|| Utf8Parser::new(DENSE_BRACKETS_INPUT).parse_bytes().len());
+  }
+
+  #[bench]
+  pub fn parse_dense_v128_bench(b: &mut Bencher) {
+    b.iter(|| Utf8Parser::new(DENSE_BRACKETS_INPUT).parse_v128().len());
+  }
+
+  #[bench]
+  pub fn parse_dense_v256_bench(b: &mut Bencher) {
+    b.iter(|| Utf8Parser::new(DENSE_BRACKETS_INPUT).parse_v256().len());
+  }
+
+  #[bench]
+  pub fn parse_dense_v128_portable_bench(b: &mut Bencher) {
+    b.iter(|| Utf8Parser::new(DENSE_BRACKETS_INPUT).parse_v128_portable().len());
+  }
+
+  #[bench]
+  pub fn parse_dense_bitmask_bench(b: &mut Bencher) {
+    b.iter(|| Utf8Parser::new(DENSE_BRACKETS_INPUT).parse_bitmask().len());
+  }
+
+  #[bench]
+  pub fn parse_structural_bench(b: &mut Bencher) {
+    b.iter(|| Utf8Parser::new(BENCHMARK_INPUT).parse_structural().len());
+  }
+
+  #[bench]
+  pub fn parse_dense_structural_bench(b: &mut Bencher) {
+    b.iter(|| Utf8Parser::new(DENSE_BRACKETS_INPUT).parse_structural().len());
+  }
+}
@@ -0,0 +1,235 @@
+//! Obsidian-style wiki-links (`[[Note]]`, `[[Note#Heading|alias]]`, `![[Note#^block]]`).
+
+use crate::brackets::*;
+use crate::parser::*;
+use crate::types::*;
+use crate::utf8::*;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Anchor {
+  /// `#Heading`, without `#`.
+  Heading(Range),
+  /// `#^block`, without `#^`.
+  Block(Range),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct WikiLink {
+  /// The whole link, including `!` of embeds.
+  pub range: Range,
+  /// Linked note. Empty for links to the same note (`[[#Heading]]`).
+  pub target: Range,
+  pub anchor: Option<Anchor>,
+  /// Text after `|`.
+  pub alias: Option<Range>,
+  /// Whether the link is an embed (`![[Note]]`).
+  pub embed: bool,
+}
+
+/// Options of the scan that [`parse_wiki_links`] is built on: `[[` and `]]` sequences with escapes and code spans,
+/// on a single line.
+#[must_use]
+pub fn wiki_link_options() -> ParseOptions {
+  ParseOptions {
+    delimiters: vec![Delimiter::sequence(b"[[", b"]]")],
+    escapes: true,
+    code: true,
+    single_line: true,
+    ..ParseOptions::default()
+  }
+}
+
+/// Finds wiki-links with the strategy.
+#[must_use]
+pub fn parse_wiki_links<'a, P: RangeParser<'a>>(input: &'a str, strategy: Strategy) -> Vec<WikiLink> {
+  let mut parser = P::with_options(input, wiki_link_options());
+  extract_wiki_links(input, parser.parse(strategy), P::ENCODING)
+}
+
+/// Splits ranges of `[[…]]` found in the input by a parser with the encoding into parts. Ranges that span multiple
+/// lines aren't wiki-links and are skipped.
+#[must_use]
+pub fn extract_wiki_links(input: &str, ranges: &[Range], encoding: Encoding) -> Vec<WikiLink> {
+  let bytes = input.as_bytes();
+
+  ranges
+    .iter()
+    .filter(|range| range.start.line == range.end.line)
+    .map(|&range| {
+      let start = range.start.offset + 2;
+      let end = range.end.offset - 2;
+      let mut cursor = Position { line: range.start.line, character: range.start.character + 2, offset: start };
+      let mut position = |offset| {
+        cursor = encoding.advance(input, cursor, offset);
+        cursor
+      };
+
+      let alias_start = find(bytes, start, end, b'|');
+      let anchor_start = find(bytes, start, alias_start, b'#');
+
+      let target = Range { start: position(start), end: position(anchor_start) };
+      let anchor = (anchor_start < alias_start).then(|| {
+        if bytes.get(anchor_start + 1) == Some(&b'^') {
+          Anchor::Block(Range { start: position(anchor_start + 2), end: position(alias_start) })
+        } else {
+          Anchor::Heading(Range { start: position(anchor_start + 1), end: position(alias_start) })
+        }
+      });
+      let alias = (alias_start < end).then(|| Range { start: position(alias_start + 1), end: position(end) });
+
+      let embed =
+        range.start.offset > 0 && bytes[range.start.offset - 1] == b'!' && !is_escaped(bytes, range.start.offset - 1);
+      let mut range = range;
+      if embed {
+        range.start.offset -= 1;
+        range.start.character -= 1;
+      }
+
+      WikiLink { range, target, anchor, alias, embed }
+    })
+    .collect()
+}
+
+/// Offset of the first `byte` between `start` and `end`, or `end`.
+fn find(bytes: &[u8], start: usize, end: usize, byte: u8) -> usize {
+  bytes[start..end].iter().position(|&b| b == byte).map_or(end, |i| start + i)
+}
+
+#[cfg(test)]
+pub mod tests {
+  extern crate test;
+  use test::bench::Bencher;
+
+  use proptest::prelude::*;
+
+  use super::*;
+  use crate::parser::Strategy;
+  use crate::tests::test_data::*;
+  use crate::utf16_parser::*;
+  use crate::utf32_parser::*;
+  use crate::utf8_parser::*;
+
+  /// Offsets of `[[…]]` found line by line without any parser.
+  fn reference_links(input: &str) -> Vec<(usize, usize)> {
+    let mut links = vec![];
+    let mut line_start = 0;
+    for line in input.split(['\n', '\r']) {
+      let mut offset = 0;
+      while let Some(start) = line[offset..].find("[[") {
+        let start = offset + start;
+        let Some(length) = line[start + 2..].find("]]") else {
+          break;
+        };
+        let end = start + length + 4;
+        links.push((line_start + start, line_start + end));
+        offset = end;
+      }
+      line_start += line.len() + 1;
+    }
+    links
+  }
+
+  fn assert_reference_links<'a, P: RangeParser<'a>>(input: &'a str) {
+    let options = ParseOptions { escapes: false, code: false, ..wiki_link_options() };
+    let expected = reference_links(input);
+    let reference = extract_wiki_links(input, P::with_options(input, options.clone()).parse_chars(), P::ENCODING);
+
+    for strategy in Strategy::ALL {
+      let mut parser = P::with_options(input, options.clone());
+      let links = extract_wiki_links(input, parser.parse(strategy), P::ENCODING);
+      let actual: Vec<_> =
+        links.iter().map(|link| (link.range.start.offset + usize::from(link.embed), link.range.end.offset)).collect();
+      assert_eq!(actual, expected, "{}", strategy.name());
+      assert_eq!(links, reference, "{}", strategy.name());
+    }
+  }
+
+  proptest! {
+    #[test]
+    fn parse_wiki_links_property_test(s in "[a-zа-я \\[\\]|#^!\\n]{300}") {
+      assert_reference_links::<Utf32Parser>(&s);
+      assert_reference_links::<Utf16Parser>(&s);
+      assert_reference_links::<Utf8Parser>(&s);
+    }
+  }
+
+  type Parts<'a> = (&'a str, Option<(bool, &'a str)>, Option<&'a str>, bool);
+
+  fn assert_wiki_links<'a, P: RangeParser<'a>>(input: &'a str, expected: &[Parts]) {
+    let text = |range: Range| &input[range.start.offset..range.end.offset];
+
+    for strategy in Strategy::ALL {
+      let actual: Vec<_> = parse_wiki_links::<P>(input, strategy)
+        .into_iter()
+        .map(|link| {
+          let anchor = link.anchor.map(|anchor| match anchor {
+            Anchor::Heading(range) => (false, text(range)),
+            Anchor::Block(range) => (true, text(range)),
+          });
+          (text(link.target), anchor, link.alias.map(text), link.embed)
+        })
+        .collect();
+      assert_eq!(actual, expected, "{}", strategy.name());
+    }
+  }
+
+  #[test]
+  pub fn parse_wiki_links_test() {
+    let input =
+      "[[Note]] ![[Диаграмма.png]] [[Note#Заголовок|alias]] [[#^block-1]] \\[[not]] `[[code]]` [[multi\nline]] \
+      [[a|b|c]] \\![[d]]";
+    let expected = [
+      ("Note", None, None, false),
+      ("Диаграмма.png", None, None, true),
+      ("Note", Some((false, "Заголовок")), Some("alias"), false),
+      ("", Some((true, "block-1")), None, false),
+      ("a", None, Some("b|c"), false),
+      ("d", None, None, false),
+    ];
+
+    assert_wiki_links::<Utf32Parser>(input, &expected);
+    assert_wiki_links::<Utf16Parser>(input, &expected);
+    assert_wiki_links::<Utf8Parser>(input, &expected);
+  }
+
+  #[test]
+  pub fn parse_wiki_links_unclosed_test() {
+    for input in ["[[a\n[[b]]", "[[a\r\n[[b]] ]]", "[[a [[x\n\n[[b]]"] {
+      assert_wiki_links::<Utf32Parser>(input, &[("b", None, None, false)]);
+      assert_wiki_links::<Utf16Parser>(input, &[("b", None, None, false)]);
+      assert_wiki_links::<Utf8Parser>(input, &[("b", None, None, false)]);
+      assert_eq!(reference_links(input).len(), 1);
+    }
+  }
+
+  #[test]
+  pub fn parse_wiki_links_characters_test() {
+    let input = "😀 ![[😀#😀|😀]]";
+    let characters = |link: WikiLink| {
+      let Some(Anchor::Heading(anchor)) = link.anchor else {
+        panic!("expected a heading anchor");
+      };
+      let alias = link.alias.unwrap();
+      [
+        link.range.start.character,
+        link.target.start.character,
+        anchor.start.character,
+        alias.start.character,
+        link.range.end.character,
+      ]
+    };
+
+    assert_eq!(characters(parse_wiki_links::<Utf32Parser>(input, Strategy::Vector128Portable)[0]), [2, 5, 7, 9, 12]);
+    assert_eq!(characters(parse_wiki_links::<Utf16Parser>(input, Strategy::Vector128Portable)[0]), [3, 6, 9, 12, 16]);
+  }
+
+  #[bench]
+  pub fn parse_wiki_links_chars_bench(b: &mut Bencher) {
+    b.iter(|| parse_wiki_links::<Utf16Parser>(BENCHMARK_INPUT, Strategy::Chars).len());
+  }
+
+  #[bench]
+  pub fn parse_wiki_links_v128_portable_bench(b: &mut Bencher) {
+    b.iter(|| parse_wiki_links::<Utf16Parser>(BENCHMARK_INPUT, Strategy::Vector128Portable).len());
+  }
+}